@@ -0,0 +1,239 @@
+use crate::preprocessor::StaticAnalysisError;
+use crate::structure::{AsmLine, AsmOperand, ArgumentDecl, Command, Condition, Declaration, Identifier, Program, Value};
+use std::collections::HashMap;
+use std::mem;
+
+/// Replaces names of constants declared in `CONST` with their values everywhere in the program,
+/// so constants never take memory cells.
+pub fn fold_constants(program: &mut Program) -> Result<(), StaticAnalysisError> {
+    let mut constants = HashMap::new();
+    for (name, value) in mem::take(&mut program.constants) {
+        if constants.insert(name.clone(), value).is_some() {
+            return Err(StaticAnalysisError::ConstantCollision(name));
+        }
+    }
+    let folding = Folding { constants };
+
+    for procedure in program.procedures.iter_mut() {
+        for argument in procedure.arguments.iter() {
+            let (ArgumentDecl::VariableArg(name) | ArgumentDecl::ArrayArg(name) | ArgumentDecl::MatrixArg(name, _)) =
+                argument;
+            folding.check_name(name)?;
+        }
+        folding.fold_declarations(&mut procedure.declarations)?;
+        folding.fold_commands(&mut procedure.commands)?;
+        if let Some(value) = procedure.returns.as_mut() {
+            folding.fold_value(value)?;
+        }
+    }
+    folding.fold_declarations(&mut program.globals)?;
+    folding.fold_declarations(&mut program.declarations)?;
+    folding.fold_commands(&mut program.commands)
+}
+
+struct Folding {
+    constants: HashMap<String, i64>,
+}
+
+impl Folding {
+    fn check_name(&self, name: &str) -> Result<(), StaticAnalysisError> {
+        if self.constants.contains_key(name) {
+            Err(StaticAnalysisError::ConstantCollision(name.to_string()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn fold_declarations(&self, declarations: &mut [Declaration]) -> Result<(), StaticAnalysisError> {
+        for declaration in declarations.iter_mut() {
+            match declaration {
+                Declaration::VariableDecl(name)
+                | Declaration::ArrayDecl(name, _, _)
+                | Declaration::MatrixDecl(name, _)
+                | Declaration::ConstantDecl(name) => self.check_name(name)?,
+                Declaration::NamedBoundsDecl(name, bounds) => {
+                    self.check_name(name)?;
+                    let bounds = bounds
+                        .iter()
+                        .map(|(first, last)| Ok((self.bound(first)?, self.bound(last)?)))
+                        .collect::<Result<_, StaticAnalysisError>>()?;
+                    *declaration = Declaration::array(mem::take(name), bounds);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn bound(&self, bound: &Value) -> Result<i64, StaticAnalysisError> {
+        match bound {
+            Value::Literal(value) => Ok(*value),
+            Value::Identifier(Identifier::Variable(name)) => self
+                .constants
+                .get(name)
+                .copied()
+                .ok_or_else(|| StaticAnalysisError::UnknownConstant(name.clone())),
+            _ => panic!("Bound of array is not a number or a name"),
+        }
+    }
+
+    fn fold_commands(&self, commands: &mut [Command]) -> Result<(), StaticAnalysisError> {
+        for command in commands.iter_mut() {
+            match command {
+                Command::Assign(identifier, operation) => {
+                    self.fold_target(identifier)?;
+                    self.fold_value(&mut operation.left)?;
+                    self.fold_value(&mut operation.right)?;
+                }
+                Command::If(condition, body)
+                | Command::While(condition, body)
+                | Command::Repeat(condition, body) => {
+                    self.fold_condition(condition)?;
+                    self.fold_commands(body)?;
+                }
+                Command::IfElse(condition, body, otherwise) => {
+                    self.fold_condition(condition)?;
+                    self.fold_commands(body)?;
+                    self.fold_commands(otherwise)?;
+                }
+                Command::For(iterator, start, end, step, body) | Command::ForDown(iterator, start, end, step, body) => {
+                    self.check_written(iterator)?;
+                    self.fold_value(start)?;
+                    self.fold_value(end)?;
+                    self.fold_value(step)?;
+                    self.fold_commands(body)?;
+                }
+                Command::FunctionCall(_, arguments) => self.check_arguments(arguments)?,
+                Command::Read(identifier) => self.fold_target(identifier)?,
+                Command::Write(value) => self.fold_value(value)?,
+                Command::Asm(lines) => {
+                    for line in lines.iter_mut() {
+                        let writes = line.writes() || line.writes_indirectly();
+                        if let AsmLine::Instruction(_, operand @ AsmOperand::Identifier(_)) = line {
+                            self.fold_operand(operand, writes)?;
+                        }
+                    }
+                }
+                Command::Case(value, branches, otherwise) => {
+                    self.fold_value(value)?;
+                    for branch in branches.iter_mut() {
+                        self.fold_commands(&mut branch.commands)?;
+                    }
+                    self.fold_commands(otherwise)?;
+                }
+                Command::Break | Command::Continue | Command::Line(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn fold_condition(&self, condition: &mut Condition) -> Result<(), StaticAnalysisError> {
+        for comparison in condition.comparisons_mut() {
+            self.fold_value(&mut comparison.left)?;
+            self.fold_value(&mut comparison.right)?;
+        }
+        Ok(())
+    }
+
+    fn fold_value(&self, value: &mut Value) -> Result<(), StaticAnalysisError> {
+        match value {
+            Value::Identifier(Identifier::Variable(name)) => {
+                if let Some(constant) = self.constants.get(name) {
+                    *value = Value::Literal(*constant);
+                }
+            }
+            Value::Identifier(identifier) => self.fold_identifier(identifier)?,
+            Value::Call(_, arguments) => self.check_arguments(arguments)?,
+            Value::Expression(operation) => {
+                self.fold_value(&mut operation.left)?;
+                self.fold_value(&mut operation.right)?;
+            }
+            Value::Literal(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Folds indices of an array element.
+    fn fold_identifier(&self, identifier: &mut Identifier) -> Result<(), StaticAnalysisError> {
+        match identifier {
+            Identifier::ArrayVar(name, index) => {
+                if let Some(constant) = self.constants.get(index) {
+                    *identifier = Identifier::ArrayLit(mem::take(name), *constant);
+                }
+            }
+            Identifier::Element(_, indices) => {
+                for index in indices.iter_mut() {
+                    self.fold_value(index)?;
+                }
+            }
+            Identifier::Variable(_) | Identifier::ArrayLit(_, _) => {}
+        }
+        Ok(())
+    }
+
+    fn fold_target(&self, identifier: &mut Identifier) -> Result<(), StaticAnalysisError> {
+        match identifier {
+            Identifier::Variable(name) => self.check_written(name),
+            identifier => self.fold_identifier(identifier),
+        }
+    }
+
+    /// Operand of `ASM` naming a constant becomes a literal, unless the instruction writes to it.
+    fn fold_operand(&self, operand: &mut AsmOperand, writes: bool) -> Result<(), StaticAnalysisError> {
+        if let AsmOperand::Identifier(identifier) = operand {
+            match identifier {
+                Identifier::Variable(name) if writes => self.check_written(name)?,
+                Identifier::Variable(name) => {
+                    if let Some(constant) = self.constants.get(name) {
+                        *operand = AsmOperand::Literal(*constant);
+                    }
+                }
+                identifier => self.fold_identifier(identifier)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn check_written(&self, name: &str) -> Result<(), StaticAnalysisError> {
+        if self.constants.contains_key(name) {
+            Err(StaticAnalysisError::ConstantModified(name.to_string()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Arguments are passed by reference, so the procedure could change a constant.
+    fn check_arguments(&self, arguments: &[String]) -> Result<(), StaticAnalysisError> {
+        arguments.iter().try_for_each(|argument| self.check_written(argument))
+    }
+}
+
+#[test]
+fn folds_constants_into_literals() {
+    use crate::grammar::program_allParser;
+    use crate::intermediate::program_translator::Translator;
+    use crate::vm::compile_and_run;
+
+    let program = "CONST n = 5, m = -3, w = 4;
+        PROCEDURE fill(T a, k) IS b[m:n] BEGIN
+            FOR i FROM m TO n DO b[i] := i * w; ENDFOR
+            FOR i FROM 0 TO n DO a[i] := b[i - 1] + k; ENDFOR
+        END
+        PROGRAM IS t[0:n], g[1:n, 0:w], x BEGIN
+            READ x;
+            fill(t, x);
+            WRITE t[n];
+            g[n, w] := n * m;
+            WRITE g[5, 4];
+            FOR i FROM 1 TO n STEP w DO WRITE i; ENDFOR
+        END";
+    for level in ["0", "2"] {
+        assert_eq!(compile_and_run(program, level, &[7]), vec![23, -15, 1, 5]);
+    }
+
+    let program = "CONST n = 5; PROGRAM IS x BEGIN READ n; END";
+    let error = Translator::new().translate(program_allParser::new().parse(program).unwrap());
+    assert!(matches!(
+        error,
+        Err(crate::intermediate::TranslationError::PreprocessorError(StaticAnalysisError::ConstantModified(_)))
+    ));
+}
@@ -0,0 +1,278 @@
+use crate::preprocessor::StaticAnalysisError;
+use crate::structure::{
+    ArgumentDecl, AsmLine, AsmOperand, Command, Condition, Declaration, Identifier, Program, Value,
+};
+use std::collections::{HashMap, HashSet};
+
+/// Makes variables and arrays of `GLOBAL` visible in procedures.
+///
+/// Globals are declared once in the main program and passed by reference to every procedure
+/// using them, directly or through procedures it calls, as arguments named `@name`,
+/// so calls are known to read and write them. Arguments and locals of a procedure
+/// shadow globals with the same name, which is reported.
+pub fn pass_globals(program: &mut Program) -> Result<Vec<String>, StaticAnalysisError> {
+    let mut report = vec![];
+    let mut globals = HashMap::new();
+    for declaration in program.globals.iter() {
+        let name = declared_name(declaration);
+        if globals.insert(name.clone(), global_argument(declaration)).is_some() {
+            return Err(StaticAnalysisError::GlobalCollision(name));
+        }
+    }
+    if let Some(name) = program
+        .declarations
+        .iter()
+        .map(declared_name)
+        .find(|name| globals.contains_key(name))
+    {
+        return Err(StaticAnalysisError::GlobalCollision(name));
+    }
+    if globals.is_empty() {
+        return Ok(report);
+    }
+
+    // Globals taken by every procedure, in order of its extra arguments
+    let mut signatures: HashMap<String, Vec<String>> = HashMap::new();
+    for procedure in program.procedures.iter_mut() {
+        let locals: HashSet<String> = procedure
+            .arguments
+            .iter()
+            .map(|argument| match argument {
+                ArgumentDecl::VariableArg(name)
+                | ArgumentDecl::ArrayArg(name)
+                | ArgumentDecl::MatrixArg(name, _) => name.clone(),
+            })
+            .chain(procedure.declarations.iter().map(declared_name))
+            .collect();
+        let mut shadowed: Vec<&String> = locals.iter().filter(|name| globals.contains_key(*name)).collect();
+        shadowed.sort();
+        for name in shadowed {
+            report.push(format!("Global {} is shadowed in procedure {}", name, procedure.name));
+        }
+
+        // Uses are found on a copy first, so calls of the procedure to itself can pass all of them
+        let mut renaming = Renaming {
+            globals: &globals,
+            signatures: &signatures,
+            procedure: Some(&procedure.name),
+            shadowed: locals.clone(),
+            used: vec![],
+        };
+        renaming.rename_commands(&mut procedure.commands.clone());
+        if let Some(mut value) = procedure.returns.clone() {
+            renaming.rename_value(&mut value);
+        }
+        let used = renaming.used;
+        signatures.insert(procedure.name.clone(), used.clone());
+
+        let mut renaming = Renaming {
+            globals: &globals,
+            signatures: &signatures,
+            procedure: Some(&procedure.name),
+            shadowed: locals,
+            used: vec![],
+        };
+        renaming.rename_commands(&mut procedure.commands);
+        if let Some(value) = procedure.returns.as_mut() {
+            renaming.rename_value(value);
+        }
+        for name in used {
+            let mut argument = globals[&name].clone();
+            match &mut argument {
+                ArgumentDecl::VariableArg(argument)
+                | ArgumentDecl::ArrayArg(argument)
+                | ArgumentDecl::MatrixArg(argument, _) => *argument = hidden_name(&name),
+            }
+            procedure.arguments.push(argument);
+        }
+    }
+
+    let mut renaming = Renaming {
+        globals: &globals,
+        signatures: &signatures,
+        procedure: None,
+        shadowed: HashSet::new(),
+        used: vec![],
+    };
+    renaming.rename_commands(&mut program.commands);
+    program.declarations.append(&mut program.globals);
+    Ok(report)
+}
+
+fn declared_name(declaration: &Declaration) -> String {
+    match declaration {
+        Declaration::VariableDecl(name)
+        | Declaration::ArrayDecl(name, _, _)
+        | Declaration::MatrixDecl(name, _)
+        | Declaration::NamedBoundsDecl(name, _)
+        | Declaration::ConstantDecl(name) => name.clone(),
+    }
+}
+
+/// Argument through which procedures reach the global.
+fn global_argument(declaration: &Declaration) -> ArgumentDecl {
+    match declaration {
+        Declaration::VariableDecl(name) | Declaration::ConstantDecl(name) => ArgumentDecl::VariableArg(name.clone()),
+        Declaration::ArrayDecl(name, _, _) => ArgumentDecl::ArrayArg(name.clone()),
+        Declaration::MatrixDecl(name, bounds) => ArgumentDecl::MatrixArg(name.clone(), bounds.len()),
+        Declaration::NamedBoundsDecl(name, _) => panic!("Bounds of {} not folded", name),
+    }
+}
+
+fn hidden_name(name: &str) -> String {
+    format!("@{}", name)
+}
+
+struct Renaming<'a> {
+    globals: &'a HashMap<String, ArgumentDecl>,
+    signatures: &'a HashMap<String, Vec<String>>,
+    /// Procedure being renamed, the main program keeps names of globals.
+    procedure: Option<&'a String>,
+    /// Arguments, locals and iterators of loops being renamed.
+    shadowed: HashSet<String>,
+    /// Globals used by the procedure or procedures it calls.
+    used: Vec<String>,
+}
+
+impl Renaming<'_> {
+    fn rename_commands(&mut self, commands: &mut [Command]) {
+        for command in commands.iter_mut() {
+            match command {
+                Command::Assign(identifier, operation) => {
+                    self.rename_identifier(identifier);
+                    self.rename_value(&mut operation.left);
+                    self.rename_value(&mut operation.right);
+                }
+                Command::If(condition, body) | Command::While(condition, body) | Command::Repeat(condition, body) => {
+                    self.rename_condition(condition);
+                    self.rename_commands(body);
+                }
+                Command::IfElse(condition, body, otherwise) => {
+                    self.rename_condition(condition);
+                    self.rename_commands(body);
+                    self.rename_commands(otherwise);
+                }
+                Command::For(iterator, start, end, step, body) | Command::ForDown(iterator, start, end, step, body) => {
+                    self.rename_value(start);
+                    self.rename_value(end);
+                    self.rename_value(step);
+                    // Iterator hides a global only inside the loop
+                    let hides = self.shadowed.insert(iterator.clone());
+                    self.rename_commands(body);
+                    if hides {
+                        self.shadowed.remove(iterator);
+                    }
+                }
+                Command::FunctionCall(name, arguments) => self.rename_call(name, arguments),
+                Command::Read(identifier) => self.rename_identifier(identifier),
+                Command::Write(value) => self.rename_value(value),
+                Command::Asm(lines) => {
+                    for line in lines.iter_mut() {
+                        if let AsmLine::Instruction(_, AsmOperand::Identifier(identifier)) = line {
+                            self.rename_identifier(identifier);
+                        }
+                    }
+                }
+                Command::Case(value, branches, otherwise) => {
+                    self.rename_value(value);
+                    for branch in branches.iter_mut() {
+                        self.rename_commands(&mut branch.commands);
+                    }
+                    self.rename_commands(otherwise);
+                }
+                Command::Break | Command::Continue | Command::Line(_) => {}
+            }
+        }
+    }
+
+    fn rename_condition(&mut self, condition: &mut Condition) {
+        for comparison in condition.comparisons_mut() {
+            self.rename_value(&mut comparison.left);
+            self.rename_value(&mut comparison.right);
+        }
+    }
+
+    fn rename_value(&mut self, value: &mut Value) {
+        match value {
+            Value::Identifier(identifier) => self.rename_identifier(identifier),
+            Value::Call(name, arguments) => self.rename_call(name, arguments),
+            Value::Expression(operation) => {
+                self.rename_value(&mut operation.left);
+                self.rename_value(&mut operation.right);
+            }
+            Value::Literal(_) => {}
+        }
+    }
+
+    fn rename_identifier(&mut self, identifier: &mut Identifier) {
+        match identifier {
+            Identifier::Variable(name) | Identifier::ArrayLit(name, _) => self.rename(name),
+            Identifier::ArrayVar(name, index) => {
+                self.rename(name);
+                self.rename(index);
+            }
+            Identifier::Element(name, indices) => {
+                self.rename(name);
+                indices.iter_mut().for_each(|index| self.rename_value(index));
+            }
+        }
+    }
+
+    /// Passes globals taken by the called procedure after its arguments.
+    fn rename_call(&mut self, name: &str, arguments: &mut Vec<String>) {
+        arguments.iter_mut().for_each(|argument| self.rename(argument));
+        let Some(taken) = self.signatures.get(name) else {
+            return;
+        };
+        for global in taken {
+            self.use_global(global);
+            arguments.push(match self.procedure {
+                Some(_) => hidden_name(global),
+                None => global.clone(),
+            });
+        }
+    }
+
+    fn rename(&mut self, name: &mut String) {
+        if self.procedure.is_some() && self.globals.contains_key(name) && !self.shadowed.contains(name) {
+            self.use_global(name);
+            *name = hidden_name(name);
+        }
+    }
+
+    fn use_global(&mut self, name: &str) {
+        if !self.used.iter().any(|used| used == name) {
+            self.used.push(name.to_string());
+        }
+    }
+}
+
+#[test]
+fn passes_globals_to_procedures() {
+    use crate::optimizer::pipeline::Options;
+    use crate::vm::{compile, compile_and_run};
+
+    let program = "GLOBAL count, t[0:4];
+        PROCEDURE tick() IS BEGIN count := count + 1; END
+        PROCEDURE put(x) IS BEGIN t[count] := x; tick(); END
+        RECURSIVE PROCEDURE fill(k) IS q BEGIN
+            IF k > 0 THEN put(k); q := k - 1; fill(q); ENDIF
+        END
+        PROCEDURE shadow(count) IS BEGIN count := 100; tick(); END
+        PROGRAM IS x, r BEGIN
+            READ x;
+            count := 0;
+            fill(x);
+            WRITE count;
+            WRITE t[2];
+            r := 5;
+            shadow(r);
+            WRITE r;
+            WRITE count;
+        END";
+    let translator = compile(program, Options::default());
+    assert!(translator.report.contains(&"Global count is shadowed in procedure shadow".to_string()));
+    for level in ["0", "2"] {
+        assert_eq!(compile_and_run(program, level, &[3]), vec![3, 1, 100, 4]);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::intermediate::{Instruction, InstructionFactory, TranslationError};
+use crate::structure::{AsmLine, AsmOperand, Identifier, Value};
+use crate::variables::{Pointer, Type, VariableDictionary};
+use std::collections::HashMap;
+
+impl InstructionFactory {
+    /// Appends instructions written in the virtual machine language, one per line.
+    ///
+    /// Operands are cell numbers, names given in `cells` or literals written as `=value`.
+    /// Jumps take a relative offset or a label defined by a `label:` line,
+    /// labels are local to the code and resolved with the rest of the program.
+    /// Comments start with `#`.
+    pub fn assemble(&mut self, code: &str, cells: &[(&str, usize)]) -> Result<(), TranslationError> {
+        let lines = parse_assembly(code)?;
+        self.assemble_lines(&lines, |identifier, _| match identifier {
+            Identifier::Variable(name) => cells
+                .iter()
+                .find(|(cell, _)| cell == name)
+                .map(|(_, cell)| Pointer::Cell(*cell))
+                .ok_or_else(|| TranslationError::Assembly(format!("Unknown cell {}", name))),
+            _ => Err(TranslationError::Assembly(format!("Unknown cell {:?}", identifier))),
+        })
+    }
+
+    /// Translates an `ASM` block, operands are resolved to cells of the variables.
+    pub(crate) fn translate_asm(
+        &mut self,
+        lines: Vec<AsmLine>,
+        variables: &mut VariableDictionary,
+    ) -> Result<(), TranslationError> {
+        self.assemble_lines(&lines, |identifier, writes| {
+            let value = Value::Identifier(identifier.clone());
+            let variable = match writes {
+                true => variables.write(value.clone())?,
+                false => variables.read(value.clone())?,
+            };
+            match variable {
+                Type::Variable(pointer) => Ok(pointer),
+                Type::Array(_, _) => Err(TranslationError::Assembly(format!(
+                    "{} has no fixed address",
+                    value
+                ))),
+            }
+        })?;
+        // The block can change any cell and jump anywhere
+        self.tracker.forget();
+        Ok(())
+    }
+
+    /// Pushes the instructions, `resolve` gives the cell of an identifier and is told
+    /// whether the instruction writes to it.
+    fn assemble_lines(
+        &mut self,
+        lines: &[AsmLine],
+        mut resolve: impl FnMut(&Identifier, bool) -> Result<Pointer, TranslationError>,
+    ) -> Result<(), TranslationError> {
+        let mut labels = HashMap::new();
+        for line in lines {
+            if let AsmLine::Label(label) = line {
+                let unique = self.reserve_label(label);
+                if labels.insert(label.as_str(), unique).is_some() {
+                    return Err(TranslationError::Assembly(format!(
+                        "Label {} is defined multiple times",
+                        label
+                    )));
+                }
+            }
+        }
+
+        for line in lines {
+            let (mnemonic, operand) = match line {
+                AsmLine::Label(label) => {
+                    self.set_label(labels[label.as_str()].clone());
+                    continue;
+                }
+                AsmLine::Instruction(mnemonic, operand) => (mnemonic.as_str(), operand),
+            };
+            let error = |message: &str| TranslationError::Assembly(format!("{} in {}", message, line));
+
+            let pointer = match operand {
+                AsmOperand::Number(cell) if *cell >= 0 => Some(Pointer::Cell(*cell as usize)),
+                AsmOperand::Literal(literal) => Some(Pointer::Literal(*literal)),
+                AsmOperand::Identifier(identifier) => Some(resolve(identifier, line.writes())?),
+                _ => None,
+            };
+            let direct = || pointer.ok_or_else(|| error("Expected a cell"));
+            let writable = || match pointer {
+                Some(Pointer::Literal(_)) => Err(error("Can not write to a literal")),
+                _ => direct(),
+            };
+            let cell = || match pointer {
+                Some(Pointer::Cell(cell)) => Ok(cell),
+                _ => Err(error("Expected a cell")),
+            };
+            let indirect = || cell().map(Pointer::IndirectCell);
+            let jump = || match operand {
+                AsmOperand::Number(offset) => Ok(Ok(*offset)),
+                AsmOperand::Label(label) => labels
+                    .get(label.as_str())
+                    .map(|label| Err(label.clone()))
+                    .ok_or_else(|| error("Unknown label")),
+                _ => Err(error("Expected an offset or a label")),
+            };
+            let no_operand = |instruction: Instruction| match operand {
+                AsmOperand::None => Ok(instruction),
+                _ => Err(error("Unexpected operand")),
+            };
+
+            let instruction = match mnemonic {
+                "GET" => Instruction::Get(cell().map(Pointer::Cell)?),
+                "PUT" => match pointer {
+                    Some(Pointer::Literal(_)) => Instruction::Put(direct()?),
+                    _ => Instruction::Put(cell().map(Pointer::Cell)?),
+                },
+                "LOAD" => Instruction::Load(direct()?),
+                "STORE" => Instruction::Store(writable()?),
+                "ADD" => Instruction::Add(direct()?),
+                "SUB" => Instruction::Subtr(direct()?),
+                "LOADI" => Instruction::Load(indirect()?),
+                "STOREI" => Instruction::Store(indirect()?),
+                "ADDI" => Instruction::Add(indirect()?),
+                "SUBI" => Instruction::Subtr(indirect()?),
+                "RTRN" => Instruction::Return(cell().map(Pointer::Cell)?),
+                "SET" => match operand {
+                    AsmOperand::Number(value) | AsmOperand::Literal(value) => Instruction::Set(*value),
+                    _ => return Err(error("Expected a value")),
+                },
+                "JUMP" => jump()?.map_or_else(Instruction::Goto, Instruction::Jump),
+                "JPOS" => jump()?.map_or_else(Instruction::GoPos, Instruction::Jpos),
+                "JZERO" => jump()?.map_or_else(Instruction::GoZero, Instruction::Jzero),
+                "JNEG" => jump()?.map_or_else(Instruction::GoNeg, Instruction::Jneg),
+                "HALF" => no_operand(Instruction::Half)?,
+                "HALT" => no_operand(Instruction::Halt)?,
+                _ => return Err(error("Unknown instruction")),
+            };
+            self.push(instruction);
+        }
+        Ok(())
+    }
+}
+
+/// Reads lines of code written for `InstructionFactory::assemble`.
+fn parse_assembly(code: &str) -> Result<Vec<AsmLine>, TranslationError> {
+    code.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            if let Some(label) = line.strip_suffix(':') {
+                return Ok(AsmLine::Label(label.to_string()));
+            }
+            let mut parts = line.split_whitespace();
+            let mnemonic = parts.next().unwrap_or_default();
+            let operand = match parts.next() {
+                None => AsmOperand::None,
+                Some(operand) => match (operand.strip_prefix('='), operand.parse()) {
+                    (Some(literal), _) => AsmOperand::Literal(literal.parse().map_err(|_| {
+                        TranslationError::Assembly(format!("Invalid literal in {}", line))
+                    })?),
+                    (None, Ok(number)) => AsmOperand::Number(number),
+                    (None, Err(_)) => {
+                        AsmOperand::Identifier(Identifier::Variable(operand.to_string()))
+                    }
+                },
+            };
+            if parts.next().is_some() {
+                return Err(TranslationError::Assembly(format!("Too many operands in {}", line)));
+            }
+            Ok(AsmLine::instruction(mnemonic, operand))
+        })
+        .collect()
+}
+
+#[test]
+fn resolves_labels_and_named_cells() {
+    let mut factory = InstructionFactory::new("Test".to_string(), 0);
+    factory
+        .assemble(
+            "loop:
+                LOAD value  # comment
+                JZERO end
+                SUB =1
+                STORE value
+                JUMP loop
+            end:
+                HALT",
+            &[("value", 5)],
+        )
+        .unwrap();
+
+    let instructions: Vec<_> = factory
+        .instructions
+        .iter()
+        .map(|line| format!("{:?}", line.instruction))
+        .collect();
+    assert_eq!(instructions[0], "Load(Cell(5))");
+    assert_eq!(instructions[2], "Subtr(Literal(1))");
+    assert!(matches!(&factory.instructions[1].instruction, Instruction::GoZero(label)
+        if factory.instructions[5].labels.contains(label)));
+    assert!(matches!(&factory.instructions[4].instruction, Instruction::Goto(label)
+        if factory.instructions[0].labels.contains(label)));
+    assert!(factory.assemble("JUMP nowhere", &[]).is_err());
+}
+
+#[test]
+fn translates_asm_blocks_with_variables() {
+    use crate::vm::compile_and_run;
+
+    let program = "PROCEDURE dec(x) IS BEGIN ASM again: LOAD x; JZERO out; SUB =1; STORE x; JUMP again; out: ENDASM END
+        PROGRAM IS a, b, c, t[1:2] BEGIN
+            READ a; READ b; READ c; dec(a); dec(b);
+            ASM LOAD c; ADD a; ADD b; ADD =1; STORE t[2]; PUT t[2]; ENDASM
+        END";
+    assert_eq!(compile_and_run(program, "2", &[2, 3, 4]), vec![5]);
+}
@@ -1,8 +1,10 @@
+use crate::intermediate::scratch::{ARGUMENT_LEFT, ARGUMENT_RIGHT, REMAINDER, RESULT};
 use crate::intermediate::{InstructionFactory, Instruction, TranslationError};
+use crate::optimizer::pipeline::Pass;
 use crate::procedures::division::DIVISION;
 use crate::procedures::multiplication::MULTIPLICATION;
 use crate::procedures::FunctionRepository;
-use crate::structure::{Identifier, Operation, Operator, Value};
+use crate::structure::{Declaration, Identifier, Operation, Operator, Value};
 use crate::variables::{Pointer, Type, VariableDictionary};
 
 impl InstructionFactory {
@@ -14,123 +16,241 @@ impl InstructionFactory {
         functions: &mut FunctionRepository,
     ) -> Result<(), TranslationError> {
         let destination = variables.write(Value::Identifier(variable))?;
-        let destination = self.prepare_pointer(destination, 1);
+        let destination = self.prepare_pointer(destination);
 
         self.action_stack.push(format!("{}", operation));
-        match &operation.operator {
-            Operator::Add => {
-                let first = variables.read(operation.left)?;
-
-                let second = variables.read(operation.right)?;
-                let second = self.prepare_pointer(second, 2);
+        self.compute(operation, variables, functions)?;
+        self.action_stack.pop();
 
-                self.load(first);
-                self.push(Instruction::Add(second));
-            }
-            Operator::Subtract => {
-                let first = variables.read(operation.left)?;
+        self.push(Instruction::Store(destination));
+        self.release_pointer(destination);
+        Ok(())
+    }
 
-                let second = variables.read(operation.right)?;
-                let second = self.prepare_pointer(second, 2);
+    /// Leaves result of the operation in the accumulator.
+    fn compute(
+        &mut self,
+        operation: Operation,
+        variables: &mut VariableDictionary,
+        functions: &mut FunctionRepository,
+    ) -> Result<(), TranslationError> {
+        let Operation { operator, left, right } = operation;
+        match operator {
+            Operator::Add | Operator::Subtract => {
+                let step = match operator {
+                    Operator::Add => Instruction::Add,
+                    _ => Instruction::Subtr,
+                };
+                // Nested operation computed last stays in the accumulator
+                let (left, right) = match (&left, &right) {
+                    (Value::Literal(_) | Value::Identifier(_), Value::Expression(_)) if operator == Operator::Add => {
+                        (right, left)
+                    }
+                    _ => (left, right),
+                };
+                let plain = match &right {
+                    Value::Literal(_) | Value::Identifier(_) => match variables.read(right.clone())? {
+                        Type::Variable(pointer) => Some(pointer),
+                        Type::Array(_, _) => None,
+                    },
+                    _ => None,
+                };
+                let left = match (left, plain) {
+                    (Value::Expression(nested), Some(second)) => {
+                        self.compute(*nested, variables, functions)?;
+                        self.push(step(second));
+                        return Ok(());
+                    }
+                    (left, _) => left,
+                };
 
-                self.load(first);
-                self.push(Instruction::Subtr(second));
+                let (first, second) = self.operands(left, right, variables, functions)?;
+                if !self.load_result(operator, first, second) {
+                    let second = self.prepare_pointer(second);
+                    self.load(first);
+                    self.push(step(second));
+                    self.release_pointer(second);
+                }
+                self.release_operand(first);
+                self.release_operand(second);
             }
             Operator::Multiply => {
-                let first_type = variables.read(operation.left)?;
-                let second_type = variables.read(operation.right)?;
-
-                self.load(first_type);
-                self.push(Instruction::Store(Pointer::Cell(6)));
-                self.load(second_type);
-                self.push(Instruction::Store(Pointer::Cell(7)));
-
-                self.call_function(
-                    MULTIPLICATION,
-                    vec![],
-                    variables,
-                    functions
-                )?;
-
-                self.load(Type::Variable(Pointer::Cell(4)));
+                let (first_type, second_type) = self.operands(left, right, variables, functions)?;
+                if !self.load_result(Operator::Multiply, first_type, second_type) {
+                    self.call_arithmetic(MULTIPLICATION, first_type, second_type, variables, functions)?;
+                    self.remember_result(RESULT, Operator::Multiply, first_type, second_type);
 
+                    self.load(Type::Variable(Pointer::Cell(RESULT)));
+                }
+                self.release_operand(first_type);
+                self.release_operand(second_type);
             }
             Operator::Divide => {
-                let first_type = variables.read(operation.left)?;
-                let second_type = variables.read(operation.right)?;
-
-                self.load(first_type);
-                self.push(Instruction::Store(Pointer::Cell(6)));
-                self.load(second_type);
-                self.push(Instruction::Store(Pointer::Cell(7)));
-
-                self.call_function(
-                    DIVISION,
-                    vec![],
-                    variables,
-                    functions
-                )?;
-
-                self.load(Type::Variable(Pointer::Cell(4)));
+                let (first_type, second_type) = self.operands(left, right, variables, functions)?;
+                if !self.load_result(Operator::Divide, first_type, second_type) {
+                    self.call_arithmetic(DIVISION, first_type, second_type, variables, functions)?;
+                    self.remember_division(first_type, second_type);
+
+                    self.load(Type::Variable(Pointer::Cell(RESULT)));
+                }
+                self.release_operand(first_type);
+                self.release_operand(second_type);
             }
             Operator::Modulo => {
-                match &operation.right {
-                    Value::Literal(2) => {
-                        let first = variables.read(operation.left)?;
+                match &right {
+                    Value::Literal(2) if self.options.is_enabled(Pass::Mod2) => {
+                        let first = self.operand(left, variables, functions)?;
                         self.load(first);
+                        self.release_operand(first);
                         self.neg_mod2();
                         self.neg()
                     }
                     _ => {
-                        let first_type = variables.read(operation.left)?;
-                        let second_type = variables.read(operation.right)?;
-
-                        self.load(first_type);
-                        self.push(Instruction::Store(Pointer::Cell(6)));
-                        self.load(second_type);
-                        self.push(Instruction::Store(Pointer::Cell(7)));
-
-                        self.call_function(
-                            DIVISION,
-                            vec![],
-                            variables,
-                            functions
-                        )?;
-
-                        self.load(Type::Variable(Pointer::Cell(2)));
+                        let (first_type, second_type) = self.operands(left, right, variables, functions)?;
+                        if !self.load_result(Operator::Modulo, first_type, second_type) {
+                            self.call_arithmetic(DIVISION, first_type, second_type, variables, functions)?;
+                            self.remember_division(first_type, second_type);
+
+                            self.load(Type::Variable(Pointer::Cell(REMAINDER)));
+                        }
+                        self.release_operand(first_type);
+                        self.release_operand(second_type);
                     }
                 }
             }
             Operator::Value => {
-                let first = variables.read(operation.left)?;
+                let first = self.operand(left, variables, functions)?;
                 self.load(first);
+                self.release_operand(first);
             }
             Operator::ShiftLeft => {
-                let first = variables.read(operation.left)?;
-                let second = match variables.read(operation.right)? {
+                let first = self.operand(left, variables, functions)?;
+                let second = match variables.read(right)? {
                     Type::Variable(Pointer::Literal(x)) => x,
                     _ => panic!("Error in shift"),
                 };
                 self.load(first);
+                self.release_operand(first);
                 for _ in 0..second {
                     self.push(Instruction::Add(Pointer::Cell(0)));
                 }
             }
             Operator::ShiftRight => {
-                let first = variables.read(operation.left)?;
-                let second = match variables.read(operation.right)? {
+                let first = self.operand(left, variables, functions)?;
+                let second = match variables.read(right)? {
                     Type::Variable(Pointer::Literal(x)) => x,
                     _ => panic!("Error in shift"),
                 };
                 self.load(first);
+                self.release_operand(first);
                 for _ in 0..second {
                     self.push(Instruction::Half);
                 }
             }
         }
+        Ok(())
+    }
+
+    /// Gives the value as an operand, nested operation is computed into a temporary
+    /// which has to be released with `release_operand`.
+    pub(crate) fn operand(
+        &mut self,
+        value: Value,
+        variables: &mut VariableDictionary,
+        functions: &mut FunctionRepository,
+    ) -> Result<Type, TranslationError> {
+        let Value::Expression(operation) = value else {
+            return Ok(variables.read(value)?);
+        };
+        self.action_stack.push(format!("{}", operation));
+        self.compute(*operation, variables, functions)?;
+
+        // Operand has to survive passing arguments to calls computing the other one
+        let mut clobbered: Vec<usize> = [MULTIPLICATION, DIVISION]
+            .iter()
+            .filter_map(|name| functions.get(*name))
+            .flat_map(|function| function.clobbers())
+            .collect();
+        clobbered.extend([ARGUMENT_LEFT, ARGUMENT_RIGHT]);
+        let place = match self.scratch.allocate_kept(&clobbered) {
+            Some(cell) => Type::Variable(Pointer::Cell(cell)),
+            None => {
+                let name = self.reserve_variable("spill");
+                variables.add(Declaration::VariableDecl(name.clone()))?;
+                variables.write(Value::Identifier(Identifier::Variable(name)))?
+            }
+        };
+        let pointer = self.prepare_pointer(place);
+        self.push(Instruction::Store(pointer));
         self.action_stack.pop();
+        Ok(Type::Variable(pointer))
+    }
 
-        self.push(Instruction::Store(destination));
-        Ok(())
+    /// Gives both values as operands, computing the deeper nested operation first.
+    pub(crate) fn operands(
+        &mut self,
+        left: Value,
+        right: Value,
+        variables: &mut VariableDictionary,
+        functions: &mut FunctionRepository,
+    ) -> Result<(Type, Type), TranslationError> {
+        if depth(&right) > depth(&left) {
+            let second = self.operand(right, variables, functions)?;
+            let first = self.operand(left, variables, functions)?;
+            Ok((first, second))
+        } else {
+            let first = self.operand(left, variables, functions)?;
+            let second = self.operand(right, variables, functions)?;
+            Ok((first, second))
+        }
+    }
+
+    pub(crate) fn release_operand(&mut self, operand: Type) {
+        if let Type::Variable(Pointer::Cell(cell)) = operand {
+            self.scratch.release(cell);
+        }
+    }
+
+    /// Calls built-in multiplication or division, passing operands in the argument cells.
+    fn call_arithmetic(
+        &mut self,
+        name: &str,
+        first: Type,
+        second: Type,
+        variables: &mut VariableDictionary,
+        functions: &mut FunctionRepository,
+    ) -> Result<(), TranslationError> {
+        self.scratch.check_call(name, &[ARGUMENT_LEFT, ARGUMENT_RIGHT]);
+        self.load(first);
+        self.push(Instruction::Store(Pointer::Cell(ARGUMENT_LEFT)));
+        self.load(second);
+        self.push(Instruction::Store(Pointer::Cell(ARGUMENT_RIGHT)));
+        self.call_function(name, vec![], variables, functions)
+    }
+}
+
+/// Number of operations nested in each other, deeper ones need more temporaries.
+fn depth(value: &Value) -> usize {
+    match value {
+        Value::Expression(operation) => 1 + depth(&operation.left).max(depth(&operation.right)),
+        _ => 0,
+    }
+}
+
+#[test]
+fn computes_nested_operations() {
+    use crate::vm::compile_and_run;
+
+    let program = "PROGRAM IS a, b, c, t[0:2] BEGIN
+            READ a; READ b; t[1] := 3;
+            c := (a + b) * (a - b) / 2 + b % 3 - -a;
+            WRITE c;
+            WRITE -(a - b * t[1]);
+            c := (((a * b) * (a * b)) * ((a * b) * (a * b))) * (((a * b) * (a * b)) * ((a * b) * (a * b)));
+            WRITE c;
+            IF a * 2 + 1 > b - 1 THEN WRITE 1; ELSE WRITE 0; ENDIF
+        END";
+    for level in ["0", "2"] {
+        assert_eq!(compile_and_run(program, level, &[7, 2]), vec![31, -1, 14_i64.pow(8), 1]);
     }
 }
@@ -0,0 +1,248 @@
+use crate::intermediate::{Instruction, InstructionFactory, TranslationError};
+use crate::procedures::FunctionRepository;
+use crate::structure::{CaseBranch, Command, Value};
+use crate::variables::{Pointer, Type, VariableDictionary};
+
+/// Largest number of ranges checked one after another, more are split in halves.
+const LINEAR_RANGES: usize = 4;
+/// Smallest number of values listed in `CASE` for which a jump table is used.
+const TABLE_MIN_VALUES: i128 = 6;
+/// Largest number of entries in a jump table.
+const TABLE_MAX_SIZE: i128 = 256;
+
+/// Range of values and number of the branch taken for them.
+type Range = (i64, i64, usize);
+
+impl InstructionFactory {
+    /// Jumps to the branch containing the value, then translates branches one after another.
+    ///
+    /// When listed values cover most of their span the branch is found with a jump table,
+    /// otherwise ranges are sorted and checked by binary search ending with linear checks.
+    pub(crate) fn translate_case(
+        &mut self,
+        value: Value,
+        branches: Vec<CaseBranch>,
+        otherwise: Vec<Command>,
+        variables: &mut VariableDictionary,
+        functions: &mut FunctionRepository,
+    ) -> Result<(), TranslationError> {
+        let end_label = self.reserve_label("case end");
+        let else_label = self.reserve_label("case else");
+        let labels: Vec<String> = branches
+            .iter()
+            .map(|_| self.reserve_label("case branch"))
+            .collect();
+
+        let mut ranges: Vec<Range> = branches
+            .iter()
+            .enumerate()
+            .flat_map(|(number, branch)| branch.ranges.iter().map(move |(first, last)| (*first, *last, number)))
+            .collect();
+        ranges.sort();
+
+        let value = variables.read(value)?;
+        let pointer = self.prepare_pointer(value);
+        if ranges.last().unwrap().1.checked_sub(ranges[0].0).is_none() {
+            // Bounds of opposite signs are too far apart to subtract, so negative values are found separately
+            let negative_label = self.reserve_label("case negative");
+            let (negative, rest) = split_at_zero(&ranges);
+            self.load(Type::Variable(pointer));
+            self.push(Instruction::GoNeg(negative_label.clone()));
+            self.find_branch(pointer, &rest, &labels, &else_label);
+            self.set_label(negative_label);
+            self.find_branch(pointer, &negative, &labels, &else_label);
+        } else {
+            self.find_branch(pointer, &ranges, &labels, &else_label);
+        }
+        self.release_pointer(pointer);
+
+        let last = branches.len() - 1;
+        for (number, (branch, label)) in branches.into_iter().zip(labels).enumerate() {
+            self.set_label(label);
+            self.action_stack.push(format!("branch {}", number));
+            self.translate_commands(branch.commands, variables, functions)?;
+            if number != last || !otherwise.is_empty() {
+                self.push(Instruction::Goto(end_label.clone()));
+            }
+            self.action_stack.pop();
+        }
+        self.set_label(else_label);
+        self.action_stack.push("else".to_string());
+        self.translate_commands(otherwise, variables, functions)?;
+        self.action_stack.pop();
+        self.set_label(end_label);
+        Ok(())
+    }
+
+    /// Jumps to the branch containing the value with a jump table or by searching sorted ranges.
+    fn find_branch(&mut self, pointer: Pointer, ranges: &[Range], labels: &[String], else_label: &str) {
+        let count: i128 = ranges
+            .iter()
+            .map(|(first, last, _)| *last as i128 - *first as i128 + 1)
+            .sum();
+        let size = ranges.last().unwrap().1 as i128 - ranges[0].0 as i128 + 1;
+        if count >= TABLE_MIN_VALUES && size <= TABLE_MAX_SIZE && size <= 2 * count {
+            self.action_stack.push("jump table".to_string());
+            self.jump_table(pointer, ranges, labels, else_label);
+        } else {
+            self.action_stack.push("search".to_string());
+            self.search(pointer, ranges, labels, else_label);
+        }
+        self.action_stack.pop();
+    }
+
+    /// Checks the middle range and searches the lower or the higher half, small parts are checked in order.
+    fn search(&mut self, pointer: Pointer, ranges: &[Range], labels: &[String], else_label: &str) {
+        if ranges.len() <= LINEAR_RANGES {
+            return self.check_ranges(pointer, ranges, labels, else_label);
+        }
+        let middle = ranges.len() / 2;
+        let (first, last, branch) = ranges[middle];
+        let lower = self.reserve_label("case lower");
+        let higher = self.reserve_label("case higher");
+
+        self.load(Type::Variable(pointer));
+        self.subtract(first);
+        self.push(Instruction::GoNeg(lower.clone()));
+        self.subtract(last - first);
+        self.push(Instruction::GoPos(higher.clone()));
+        self.push(Instruction::Goto(labels[branch].clone()));
+
+        self.set_label(lower);
+        self.search(pointer, &ranges[..middle], labels, else_label);
+        self.set_label(higher);
+        self.search(pointer, &ranges[middle + 1..], labels, else_label);
+    }
+
+    /// Checks sorted ranges in order, the accumulator keeps the value minus the last checked bound.
+    fn check_ranges(&mut self, pointer: Pointer, ranges: &[Range], labels: &[String], else_label: &str) {
+        self.load(Type::Variable(pointer));
+        let mut bound = 0;
+        for (first, last, branch) in ranges.iter().copied() {
+            self.subtract(first - bound);
+            if first == last {
+                self.push(Instruction::GoZero(labels[branch].clone()));
+            } else {
+                // Value is lower than every range left
+                self.push(Instruction::GoNeg(else_label.to_string()));
+                self.subtract(last - first);
+                self.push(Instruction::GoNeg(labels[branch].clone()));
+                self.push(Instruction::GoZero(labels[branch].clone()));
+            }
+            bound = last;
+        }
+        self.push(Instruction::Goto(else_label.to_string()));
+    }
+
+    /// Returns to an entry of a table of jumps to branches, chosen by the value.
+    fn jump_table(&mut self, pointer: Pointer, ranges: &[Range], labels: &[String], else_label: &str) {
+        let (first, last) = (ranges[0].0, ranges.last().unwrap().1);
+        let table_end = self.reserve_label("case table");
+
+        self.load(Type::Variable(pointer));
+        self.subtract(first);
+        self.push(Instruction::GoNeg(else_label.to_string()));
+        self.subtract(last - first);
+        self.push(Instruction::GoPos(else_label.to_string()));
+
+        // Value minus the last bound is added to the address of the last entry
+        let entry = self.scratch.allocate(|_| false);
+        self.push(Instruction::Store(Pointer::Cell(entry)));
+        self.push(Instruction::LoadLabel(table_end.clone()));
+        self.push(Instruction::Add(Pointer::Cell(entry)));
+        self.push(Instruction::Store(Pointer::Cell(entry)));
+        self.push(Instruction::Return(Pointer::Cell(entry)));
+        self.scratch.release(entry);
+
+        let mut ranges = ranges.iter().peekable();
+        for value in first..=last {
+            while ranges.next_if(|(_, range_last, _)| *range_last < value).is_some() {}
+            let target = match ranges.peek() {
+                Some((range_first, _, branch)) if *range_first <= value => labels[*branch].clone(),
+                _ => else_label.to_string(),
+            };
+            if value == last {
+                self.set_label(table_end.clone());
+            }
+            self.push(Instruction::Goto(target));
+        }
+    }
+
+    fn subtract(&mut self, amount: i64) {
+        if amount != 0 {
+            self.push(Instruction::Subtr(Pointer::Literal(amount)));
+        }
+    }
+}
+
+/// Splits sorted ranges into negative values and the rest, dividing the range containing zero.
+fn split_at_zero(ranges: &[Range]) -> (Vec<Range>, Vec<Range>) {
+    let mut negative = vec![];
+    let mut rest = vec![];
+    for (first, last, branch) in ranges.iter().copied() {
+        if first < 0 {
+            negative.push((first, last.min(-1), branch));
+        }
+        if last >= 0 {
+            rest.push((first.max(0), last, branch));
+        }
+    }
+    (negative, rest)
+}
+
+#[test]
+fn jumps_to_branches_of_case() {
+    use crate::optimizer::pipeline::Options;
+    use crate::vm::{compile, run};
+
+    let program = "PROGRAM IS n, r BEGIN
+            READ n; r := 0;
+            CASE n OF 1: WRITE 1; | 2, 3: WRITE 2; | 10..20: WRITE 3; ELSE WRITE 0; ENDCASE
+            CASE n OF 0: r := 10; | 1: r := 11; | 2: r := 12; | 3, 4: r := 13; | 5..7: r := 14; ENDCASE
+            WRITE r;
+            CASE n OF -5..-1: WRITE 5; | 2: WRITE 6; | 4: WRITE 7; | 8: WRITE 8; | 16: WRITE 9;
+                | 32..40: WRITE 10; | 64: WRITE 11; ELSE WRITE 12; ENDCASE
+        END";
+    let cases = [
+        (1, [1, 11, 12]),
+        (3, [2, 13, 12]),
+        (4, [0, 13, 7]),
+        (15, [3, 0, 12]),
+        (20, [3, 0, 12]),
+        (-3, [0, 0, 5]),
+        (36, [0, 0, 10]),
+        (64, [0, 0, 11]),
+    ];
+    for level in ["0", "2"] {
+        let code = compile(program, Options::level(level).unwrap()).to_code(false);
+        assert!(code.contains("RTRN"));
+        for (input, output) in cases {
+            let execution = run(&code, &[input], 1_000_000).unwrap();
+            assert_eq!(execution.output, output, "input {}", input);
+        }
+    }
+}
+
+#[test]
+fn finds_branches_of_ranges_far_apart() {
+    use crate::vm::compile_and_run;
+
+    let program = "PROGRAM IS n BEGIN
+            READ n;
+            CASE n OF -5000000000000000000..0: WRITE 1; | 1..5000000000000000000: WRITE 2; ENDCASE
+            CASE n OF -9223372036854775807..-1: WRITE 3; | 3, 7..9223372036854775807: WRITE 4; ELSE WRITE 5; ENDCASE
+        END";
+    let cases = [
+        (3, vec![2, 4]),
+        (0, vec![1, 5]),
+        (-7, vec![1, 3]),
+        (5, vec![2, 5]),
+        (9223372036854775807, vec![4]),
+        (-9223372036854775807, vec![3]),
+    ];
+    for level in ["0", "2"] {
+        for (input, output) in cases.iter() {
+            assert_eq!(&compile_and_run(program, level, &[*input]), output, "input {}", input);
+        }
+    }
+}
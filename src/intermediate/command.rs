@@ -1,7 +1,10 @@
 use crate::intermediate::{Instruction, InstructionFactory, TranslationError};
+use crate::optimizer::pipeline::Pass;
+use crate::optimizer::unroll::{partial_factor, trip_count};
+use crate::preprocessor::StaticAnalysisError;
 use crate::procedures::FunctionRepository;
-use crate::structure::{Command, Identifier, Value};
-use crate::variables::{Pointer, VariableDictionary};
+use crate::structure::{has_break, has_continue, Command, Identifier, Value};
+use crate::variables::{Pointer, Type, VariableDictionary};
 
 impl InstructionFactory {
     pub(crate) fn translate_command(
@@ -20,7 +23,7 @@ impl InstructionFactory {
                 self.action_stack.push("If".to_string());
 
                 let end_label = self.reserve_label("if on false");
-                self.handle_condition(condition, variables, &end_label)?;
+                self.handle_condition(condition, variables, functions, &end_label)?;
 
                 self.action_stack.push("true".to_string());
                 self.translate_commands(commands, variables, functions)?;
@@ -34,7 +37,7 @@ impl InstructionFactory {
                 let end_label = self.reserve_label("ifelse end");
                 let else_label = self.reserve_label("ifelse else");
 
-                self.handle_condition(condition, variables, &else_label)?;
+                self.handle_condition(condition, variables, functions, &else_label)?;
 
                 self.action_stack.push("true".to_string());
                 self.translate_commands(yes, variables, functions)?;
@@ -48,34 +51,53 @@ impl InstructionFactory {
 
                 self.set_label(end_label);
             }
-            Command::While(condition, commands) => {
+            Command::While(condition, mut commands) => {
                 self.action_stack.push("While".to_string());
+                let hoisted = self.hoist_invariants(Some(&condition), None, &mut commands, variables, functions)?;
 
                 let start_label = self.reserve_label("while start");
                 let end_label = self.reserve_label("while end");
                 self.set_label(start_label.clone());
-                self.handle_condition(condition, variables, &end_label)?;
+                self.handle_condition(condition, variables, functions, &end_label)?;
+                self.loops.push((start_label.clone(), end_label.clone()));
                 self.translate_commands(commands, variables, functions)?;
+                self.loops.pop();
                 self.push(Instruction::Goto(start_label));
                 self.set_label(end_label);
+                self.release_hoisted(hoisted);
             }
-            Command::Repeat(condition, commands) => {
+            Command::Repeat(condition, mut commands) => {
                 self.action_stack.push("Repeat".to_string());
+                let hoisted = self.hoist_invariants(Some(&condition), None, &mut commands, variables, functions)?;
 
                 let start_label = self.reserve_label("repeat start");
+                let check_label = self.reserve_label("repeat check");
+                let end_label = self.reserve_label("repeat end");
+                let (continues, breaks) = (has_continue(&commands), has_break(&commands));
                 self.set_label(start_label.clone());
+                self.loops.push((check_label.clone(), end_label.clone()));
                 self.translate_commands(commands, variables, functions)?;
-                self.handle_condition(condition, variables, &start_label)?;
+                self.loops.pop();
+                // Labels forget known values, so they are only set when something jumps to them
+                if continues {
+                    self.set_label(check_label);
+                }
+                self.handle_condition(condition, variables, functions, &start_label)?;
+                if breaks {
+                    self.set_label(end_label);
+                }
+                self.release_hoisted(hoisted);
             }
-            Command::For(iter, start, end, commands) => {
+            Command::For(iter, start, end, step, mut commands) => {
                 self.action_stack.push("For".to_string());
+                let factor = self.unroll_factor(trip_count(&start, &end, &step), &commands);
 
                 let iter_end_type = variables.read(Value::Identifier(Identifier::Variable(
                     format!("{}_end", iter),
                 )))?;
-                let iter_end_ptr = self.prepare_pointer(iter_end_type, 2);
-                let iter_type = variables.read(Value::Identifier(Identifier::Variable(iter)))?;
-                let iter_ptr = self.prepare_pointer(iter_type, 1);
+                let iter_end_ptr = self.prepare_pointer(iter_end_type);
+                let iter_type = variables.read(Value::Identifier(Identifier::Variable(iter.clone())))?;
+                let iter_ptr = self.prepare_pointer(iter_type);
 
                 let start = variables.read(start)?;
                 self.load(start);
@@ -83,27 +105,54 @@ impl InstructionFactory {
                 let end = variables.read(end)?;
                 self.load(end);
                 self.push(Instruction::Store(iter_end_ptr));
+                let end_label = self.reserve_label("for end");
+                let step_ptr = self.loop_step(&iter, step, &end_label, variables)?;
+                let hoisted = self.hoist_invariants(None, Some(&iter), &mut commands, variables, functions)?;
+                let induction = self.reduce_induction(&iter, &commands, variables)?;
 
                 let start_label = self.reserve_label("for start");
+                let next_label = self.reserve_label("for next");
+                let (continues, breaks) = (has_continue(&commands), has_break(&commands));
+                let skips = !matches!(step_ptr, Pointer::Literal(_));
                 self.set_label(start_label.clone());
-                self.translate_commands(commands, variables, functions)?;
+                self.loops.push((next_label.clone(), end_label.clone()));
+                self.translate_copies(commands, factor, variables, functions, |factory| {
+                    factory.step_induction(&induction, Instruction::Add, step_ptr);
+                    factory.load(iter_type);
+                    factory.push(Instruction::Add(step_ptr));
+                    factory.push(Instruction::Store(iter_ptr));
+                })?;
+                self.loops.pop();
 
+                // Iterator is moved to the next value also after `CONTINUE`
+                if continues {
+                    self.set_label(next_label);
+                }
+                self.step_induction(&induction, Instruction::Add, step_ptr);
                 self.load(iter_type);
-                self.push(Instruction::Add(Pointer::Literal(1)));
+                self.push(Instruction::Add(step_ptr));
                 self.push(Instruction::Store(iter_ptr));
                 self.push(Instruction::Subtr(iter_end_ptr));
                 self.push(Instruction::Jpos(2));
                 self.push(Instruction::Goto(start_label));
+                if breaks || skips {
+                    self.set_label(end_label);
+                }
+                self.release_hoisted(hoisted + induction.len());
+                self.release_pointer(step_ptr);
+                self.release_pointer(iter_ptr);
+                self.release_pointer(iter_end_ptr);
             }
-            Command::ForDown(iter, start, end, commands) => {
+            Command::ForDown(iter, start, end, step, mut commands) => {
                 self.action_stack.push("ForDown".to_string());
+                let factor = self.unroll_factor(trip_count(&end, &start, &step), &commands);
 
                 let iter_end_type = variables.read(Value::Identifier(Identifier::Variable(
                     format!("{}_end", iter),
                 )))?;
-                let iter_end_ptr = self.prepare_pointer(iter_end_type, 2);
-                let iter_type = variables.read(Value::Identifier(Identifier::Variable(iter)))?;
-                let iter_ptr = self.prepare_pointer(iter_type, 1);
+                let iter_end_ptr = self.prepare_pointer(iter_end_type);
+                let iter_type = variables.read(Value::Identifier(Identifier::Variable(iter.clone())))?;
+                let iter_ptr = self.prepare_pointer(iter_type);
 
                 let start = variables.read(start)?;
                 self.load(start);
@@ -111,16 +160,43 @@ impl InstructionFactory {
                 let end = variables.read(end)?;
                 self.load(end);
                 self.push(Instruction::Store(iter_end_ptr));
+                let end_label = self.reserve_label("for end");
+                let step_ptr = self.loop_step(&iter, step, &end_label, variables)?;
+                let hoisted = self.hoist_invariants(None, Some(&iter), &mut commands, variables, functions)?;
+                let induction = self.reduce_induction(&iter, &commands, variables)?;
 
                 let start_label = self.reserve_label("for start");
+                let next_label = self.reserve_label("for next");
+                let (continues, breaks) = (has_continue(&commands), has_break(&commands));
+                let skips = !matches!(step_ptr, Pointer::Literal(_));
                 self.set_label(start_label.clone());
-                self.translate_commands(commands, variables, functions)?;
+                self.loops.push((next_label.clone(), end_label.clone()));
+                self.translate_copies(commands, factor, variables, functions, |factory| {
+                    factory.step_induction(&induction, Instruction::Subtr, step_ptr);
+                    factory.load(iter_type);
+                    factory.push(Instruction::Subtr(step_ptr));
+                    factory.push(Instruction::Store(iter_ptr));
+                })?;
+                self.loops.pop();
+
+                // Iterator is moved to the next value also after `CONTINUE`
+                if continues {
+                    self.set_label(next_label);
+                }
+                self.step_induction(&induction, Instruction::Subtr, step_ptr);
                 self.load(iter_type);
-                self.push(Instruction::Subtr(Pointer::Literal(1)));
+                self.push(Instruction::Subtr(step_ptr));
                 self.push(Instruction::Store(iter_ptr));
                 self.push(Instruction::Subtr(iter_end_ptr));
                 self.push(Instruction::Jneg(2));
                 self.push(Instruction::Goto(start_label));
+                if breaks || skips {
+                    self.set_label(end_label);
+                }
+                self.release_hoisted(hoisted + induction.len());
+                self.release_pointer(step_ptr);
+                self.release_pointer(iter_ptr);
+                self.release_pointer(iter_end_ptr);
             }
             Command::FunctionCall(name, arguments) => {
                 self.action_stack.push(format!("Call function {}", name));
@@ -134,10 +210,179 @@ impl InstructionFactory {
             }
             Command::Write(value) => {
                 self.action_stack.push(format!("Write {}", value));
-                self.write(variables.read(value)?);
+                let value = self.operand(value, variables, functions)?;
+                self.write(value);
+                self.release_operand(value);
+            }
+            Command::Break => {
+                self.action_stack.push("Break".to_string());
+                let (_, end) = self.loops.last().ok_or(StaticAnalysisError::OutsideLoop)?.clone();
+                self.push(Instruction::Goto(end));
+            }
+            Command::Continue => {
+                self.action_stack.push("Continue".to_string());
+                let (next, _) = self.loops.last().ok_or(StaticAnalysisError::OutsideLoop)?.clone();
+                self.push(Instruction::Goto(next));
             }
+            Command::Case(value, branches, otherwise) => {
+                self.action_stack.push(format!("Case {}", value));
+                self.translate_case(value, branches, otherwise, variables, functions)?;
+            }
+            Command::Asm(lines) => {
+                self.action_stack.push("Asm".to_string());
+                self.translate_asm(lines, variables)?;
+            }
+            Command::Line(line) => self.action_stack.push(format!("Line {}", line)),
         }
         self.action_stack.pop();
         Ok(())
     }
+
+    /// Gives the step of a loop, step given by a variable is copied
+    /// so that changing the variable in the body does not affect the loop.
+    /// Loop with a step which is not positive is skipped by jumping to `end`.
+    fn loop_step(
+        &mut self,
+        iterator: &str,
+        step: Value,
+        end: &str,
+        variables: &mut VariableDictionary,
+    ) -> Result<Pointer, TranslationError> {
+        let step = match variables.read(step)? {
+            Type::Variable(Pointer::Literal(step)) => return Ok(Pointer::Literal(step)),
+            step => step,
+        };
+        let fixed = variables.read(Value::Identifier(Identifier::Variable(format!(
+            "{}_step",
+            iterator
+        ))))?;
+        let fixed = self.prepare_pointer(fixed);
+        self.load(step);
+        self.push(Instruction::Store(fixed));
+        self.push(Instruction::Jpos(2));
+        self.push(Instruction::Goto(end.to_string()));
+        Ok(fixed)
+    }
+
+    fn unroll_factor(&self, count: Option<i64>, commands: &[Command]) -> i64 {
+        match self.options.is_enabled(Pass::Unroll) {
+            true => partial_factor(count, commands),
+            false => 1,
+        }
+    }
+
+    /// Translates `factor` copies of the loop body, moving to the next iteration between them.
+    fn translate_copies(
+        &mut self,
+        commands: Vec<Command>,
+        factor: i64,
+        variables: &mut VariableDictionary,
+        functions: &mut FunctionRepository,
+        next_iteration: impl Fn(&mut Self),
+    ) -> Result<(), TranslationError> {
+        for _ in 1..factor {
+            self.translate_commands(commands.clone(), variables, functions)?;
+            next_iteration(self);
+        }
+        self.translate_commands(commands, variables, functions)
+    }
+}
+
+#[test]
+fn leaves_and_continues_loops() {
+    use crate::vm::compile_and_run;
+
+    let program = "PROGRAM IS n, i, s, t[0:9] BEGIN
+            READ n;
+            i := 0; s := 0;
+            WHILE i < n DO
+                i := i + 1;
+                IF i % 2 = 0 THEN CONTINUE; ENDIF
+                IF i > 7 THEN BREAK; ENDIF
+                s := s + i;
+            ENDWHILE
+            WRITE s;
+            s := 0;
+            FOR j FROM 0 TO 9 DO
+                t[j] := j;
+                IF j = 3 THEN CONTINUE; ENDIF
+                s := s + t[j];
+                FOR k FROM j DOWNTO 0 DO
+                    IF k = 1 THEN BREAK; ENDIF
+                    s := s + 100;
+                ENDFOR
+                IF j = n THEN BREAK; ENDIF
+            ENDFOR
+            WRITE s;
+            i := 0; s := 0;
+            REPEAT
+                i := i + 1;
+                IF i = 2 THEN CONTINUE; ENDIF
+                s := s + i;
+                IF s > 20 THEN BREAK; ENDIF
+            UNTIL i >= n;
+            WRITE s;
+        END";
+    for level in ["0", "2"] {
+        assert_eq!(compile_and_run(program, level, &[9]), vec![16, 3542, 26]);
+    }
+}
+
+#[test]
+fn rejects_break_outside_loops() {
+    use crate::procedures::FunctionRepository;
+
+    let mut factory = InstructionFactory::new("Break".to_string(), 0);
+    let mut variables = VariableDictionary::new(crate::intermediate::scratch::SCRATCH_END);
+    let result = factory.translate_commands(vec![Command::Break], &mut variables, &mut FunctionRepository::new());
+    assert!(matches!(
+        result,
+        Err(TranslationError::PreprocessorError(StaticAnalysisError::OutsideLoop))
+    ));
+}
+
+#[test]
+fn steps_loops_by_given_amount() {
+    use crate::vm::compile_and_run;
+
+    let program = "PROGRAM IS n, s, t[0:20] BEGIN
+            READ n;
+            s := 0;
+            FOR i FROM 1 TO 10 STEP 4 DO s := s + i; ENDFOR
+            WRITE s;
+            FOR i FROM 0 TO 20 STEP n DO t[i] := i; n := n + 1; ENDFOR
+            WRITE n;
+            s := 0;
+            FOR i FROM 20 DOWNTO 0 STEP 5 DO s := s + t[i]; ENDFOR
+            WRITE s;
+            FOR i FROM 0 TO 2 STEP 2 DO
+                FOR j FROM i DOWNTO 0 STEP 2 DO WRITE j; ENDFOR
+            ENDFOR
+        END";
+    for level in ["0", "2"] {
+        assert_eq!(compile_and_run(program, level, &[5]), vec![15, 10, 50, 0, 2, 0]);
+    }
+}
+
+#[test]
+fn skips_loops_with_non_positive_step() {
+    use crate::vm::compile_and_run;
+
+    let program = "PROGRAM IS k, s BEGIN
+            READ k;
+            s := 0;
+            FOR i FROM 1 TO 3 STEP k DO s := s + i; ENDFOR
+            WRITE s;
+            FOR i FROM 3 DOWNTO 1 STEP k DO s := s + i; ENDFOR
+            WRITE s;
+            FOR i FROM 9223372036854775800 TO 9223372036854775807 STEP k DO WRITE i; ENDFOR
+        END";
+    for level in ["0", "2"] {
+        assert_eq!(compile_and_run(program, level, &[0]), vec![0, 0]);
+        assert_eq!(compile_and_run(program, level, &[-2]), vec![0, 0]);
+        assert_eq!(
+            compile_and_run(program, level, &[5]),
+            vec![1, 4, 9223372036854775800, 9223372036854775805]
+        );
+    }
 }
@@ -1,86 +1,117 @@
 use crate::intermediate::{InstructionFactory, Instruction, TranslationError};
-use crate::structure::{Condition, ConditionOperator};
+use crate::procedures::FunctionRepository;
+use crate::structure::{Comparison, Condition, ConditionOperator};
 use crate::variables::{Pointer, VariableDictionary};
 
 impl InstructionFactory {
-    pub(crate) fn handle_condition(&mut self, condition: Condition, variables: &mut VariableDictionary, label: &String) -> Result<(), TranslationError> {
+    /// Jumps to `label` when the condition is false, continues when it is true.
+    pub(crate) fn handle_condition(&mut self, condition: Condition, variables: &mut VariableDictionary, functions: &mut FunctionRepository, label: &str) -> Result<(), TranslationError> {
         self.action_stack.push(format!("Condition {}", condition));
+        self.branch(condition, false, variables, functions, label)?;
+        self.action_stack.pop();
+        Ok(())
+    }
 
-        let Condition { left, right, operator } = condition;
+    /// Jumps to `label` when the condition is `when`, parts of `AND` and `OR` are checked
+    /// only until the result is known.
+    fn branch(&mut self, condition: Condition, when: bool, variables: &mut VariableDictionary, functions: &mut FunctionRepository, label: &str) -> Result<(), TranslationError> {
+        match (condition, when) {
+            (Condition::Compare(comparison), false) => self.compare(comparison, variables, functions, label),
+            (Condition::Compare(mut comparison), true) => {
+                comparison.operator = comparison.operator.negated();
+                self.compare(comparison, variables, functions, label)
+            }
+            (Condition::Not(condition), _) => self.branch(*condition, !when, variables, functions, label),
+            (Condition::And(first, second), false) | (Condition::Or(first, second), true) => {
+                self.branch(*first, when, variables, functions, label)?;
+                self.branch(*second, when, variables, functions, label)
+            }
+            (Condition::And(first, second), true) | (Condition::Or(first, second), false) => {
+                // Second part is checked only when the first one does not decide
+                let decided = self.reserve_label("short circuit");
+                self.branch(*first, !when, variables, functions, &decided)?;
+                self.branch(*second, when, variables, functions, label)?;
+                self.set_label(decided);
+                Ok(())
+            }
+        }
+    }
+
+    /// Jumps to `label` when the comparison is false, after running commands computing its operands.
+    fn compare(&mut self, comparison: Comparison, variables: &mut VariableDictionary, functions: &mut FunctionRepository, label: &str) -> Result<(), TranslationError> {
+        let Comparison { left, right, operator, before } = comparison;
+        self.translate_commands(before, variables, functions)?;
+
+        let (left, right) = self.operands(left, right, variables, functions)?;
+        let right_pointer = self.prepare_pointer(right);
+
+        self.load(left);
+        if !matches!(right_pointer, Pointer::Literal(0)) {
+            self.push(Instruction::Subtr(right_pointer));
+        }
+        self.release_pointer(right_pointer);
+        self.release_operand(left);
+        self.release_operand(right);
 
         match operator {
             ConditionOperator::Equal => {
-                let left = variables.read(left)?;
-                let right = variables.read(right)?;
-                let right = self.prepare_pointer(right, 2);
-
-                self.load(left);
-                if !matches!(right, Pointer::Literal(0)) {
-                    self.push(Instruction::Subtr(right));
-                }
                 self.push(Instruction::Jzero(2));
-                self.push(Instruction::Goto(label.clone()));
+                self.push(Instruction::Goto(label.to_string()));
             }
             ConditionOperator::NotEqual => {
-                let left = variables.read(left)?;
-                let right = variables.read(right)?;
-                let right = self.prepare_pointer(right, 2);
-
-                self.load(left);
-                if !matches!(right, Pointer::Literal(0)) {
-                    self.push(Instruction::Subtr(right));
-                }
-                self.push(Instruction::GoZero(label.clone()));
+                self.push(Instruction::GoZero(label.to_string()));
             }
             ConditionOperator::Lesser => {
-                let left = variables.read(left)?;
-                let right = variables.read(right)?;
-                let right = self.prepare_pointer(right, 2);
-
-                self.load(left);
-                if !matches!(right, Pointer::Literal(0)) {
-                    self.push(Instruction::Subtr(right));
-                }
                 self.push(Instruction::Jneg(2));
-                self.push(Instruction::Goto(label.clone()));
+                self.push(Instruction::Goto(label.to_string()));
             }
             ConditionOperator::Greater => {
-                let left = variables.read(left)?;
-                let right = variables.read(right)?;
-                let right = self.prepare_pointer(right, 2);
-
-                self.load(left);
-                if !matches!(right, Pointer::Literal(0)) {
-                    self.push(Instruction::Subtr(right));
-                }
                 self.push(Instruction::Jpos(2));
-                self.push(Instruction::Goto(label.clone()));
+                self.push(Instruction::Goto(label.to_string()));
             }
             ConditionOperator::LesserEqual => {
-                let left = variables.read(left)?;
-                let right = variables.read(right)?;
-                let right = self.prepare_pointer(right, 2);
-
-                self.load(left);
-                if !matches!(right, Pointer::Literal(0)) {
-                    self.push(Instruction::Subtr(right));
-                }
-                self.push(Instruction::GoPos(label.clone()));
+                self.push(Instruction::GoPos(label.to_string()));
             }
             ConditionOperator::GreaterEqual => {
-                let left = variables.read(left)?;
-                let right = variables.read(right)?;
-                let right = self.prepare_pointer(right, 2);
-
-                self.load(left);
-                if !matches!(right, Pointer::Literal(0)) {
-                    self.push(Instruction::Subtr(right));
-                }
-                self.push(Instruction::GoNeg(label.clone()));
+                self.push(Instruction::GoNeg(label.to_string()));
             }
         }
-
-        self.action_stack.pop();
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[test]
+fn checks_compound_conditions_with_jumps() {
+    use crate::vm::compile_and_run;
+
+    let program = "PROGRAM IS a, b, r BEGIN
+            FOR i FROM 0 TO 2 DO FOR j FROM 0 TO 2 DO
+                a := i; b := j; r := 0;
+                IF a > 0 AND b > 0 THEN r := r + 1; ENDIF
+                IF a = 0 OR NOT (b < 2) THEN r := r + 10; ENDIF
+                IF NOT (a = 1 OR b = 1) AND (a + b) % 2 = 0 THEN r := r + 100; ENDIF
+                IF 1 > 2 OR a = b THEN r := r + 1000; ENDIF
+                WRITE r;
+            ENDFOR ENDFOR
+        END";
+    let expected = vec![1110, 10, 110, 0, 1001, 11, 100, 1, 1111];
+    for level in ["0", "2"] {
+        assert_eq!(compile_and_run(program, level, &[]), expected);
+    }
+}
+
+#[test]
+fn calls_functions_only_when_their_comparison_is_checked() {
+    use crate::vm::compile_and_run;
+
+    let program = "FUNCTION f(n) IS r BEGIN n := n + 1; r := n; RETURN r; END
+        PROGRAM IS a, c BEGIN
+            READ a; c := 0;
+            IF a > 0 AND f(c) > 0 THEN WRITE 1; ELSE WRITE 0; ENDIF
+            WHILE c < 3 AND f(c) < 10 DO WRITE c; ENDWHILE
+            WRITE c;
+        END";
+    for level in ["0", "2"] {
+        assert_eq!(compile_and_run(program, level, &[0]), vec![0, 1, 2, 3, 3]);
+        assert_eq!(compile_and_run(program, level, &[1]), vec![1, 2, 3, 3]);
+    }
+}
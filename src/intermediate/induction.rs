@@ -0,0 +1,178 @@
+use crate::intermediate::invariant::{collect_commands, Writes};
+use crate::intermediate::{Instruction, InstructionFactory, TranslationError};
+use crate::optimizer::pipeline::Pass;
+use crate::structure::{Command, Condition, Declaration, Identifier, Value};
+use crate::variables::{Pointer, Type, VariableDictionary};
+
+/// Estimated cost saved on every read of array element through pointer.
+const READ_SAVING: usize = 20;
+/// Estimated cost saved on every write to array element through pointer.
+const WRITE_SAVING: usize = 30;
+/// Cost of moving pointer with the iterator.
+const STEP_COST: usize = 30;
+/// Estimated number of iterations of nested loops.
+const NESTED_LOOP_WEIGHT: usize = 10;
+
+impl InstructionFactory {
+    /// Replaces computing addresses of array elements indexed by the loop iterator
+    /// with pointers moved together with the iterator.
+    ///
+    /// Pointers are set to the first accessed element, returned pointers have to be
+    /// stepped after every iteration and released after translating the loop.
+    pub(crate) fn reduce_induction(
+        &mut self,
+        iterator: &str,
+        commands: &[Command],
+        variables: &mut VariableDictionary,
+    ) -> Result<Vec<Pointer>, TranslationError> {
+        if !self.options.is_enabled(Pass::Induction)
+            || variables.frame().is_some()
+            || Writes::of(None, commands, variables).writes_variable(iterator, variables)
+        {
+            return Ok(vec![]);
+        }
+
+        let mut identifiers = vec![];
+        collect_commands(commands, &mut identifiers);
+        let mut arrays: Vec<&String> = vec![];
+        for identifier in identifiers {
+            if let Identifier::ArrayVar(array, index) = identifier {
+                if index == iterator && !arrays.contains(&array) {
+                    arrays.push(array);
+                }
+            }
+        }
+
+        let mut pointers = vec![];
+        for array in arrays {
+            if savings(commands, array, iterator, 1) <= STEP_COST {
+                continue;
+            }
+            let element = Identifier::ArrayVar(array.clone(), iterator.to_string());
+            let Type::Array(offset, index) = variables.read(Value::Identifier(element.clone()))?
+            else {
+                continue;
+            };
+
+            let name = self.reserve_variable("induction");
+            variables.add(Declaration::VariableDecl(name.clone()))?;
+            let cell = match variables.write(Value::Identifier(Identifier::Variable(name)))? {
+                Type::Variable(Pointer::Cell(cell)) => cell,
+                _ => panic!("Induction pointer is not a cell"),
+            };
+
+            self.action_stack
+                .push(format!("pointer to {}", Value::Identifier(element)));
+            self.load_address(offset, index);
+            self.push(Instruction::Store(Pointer::Cell(cell)));
+            self.action_stack.pop();
+
+            self.hoisted_addresses
+                .push((offset, index, Pointer::IndirectCell(cell)));
+            pointers.push(Pointer::Cell(cell));
+        }
+        Ok(pointers)
+    }
+
+    /// Moves pointers by `amount` elements, `step` is either `Instruction::Add` or `Instruction::Subtr`.
+    pub(crate) fn step_induction(
+        &mut self,
+        pointers: &[Pointer],
+        step: fn(Pointer) -> Instruction,
+        amount: Pointer,
+    ) {
+        for pointer in pointers {
+            self.push(Instruction::Load(*pointer));
+            self.push(step(amount));
+            self.push(Instruction::Store(*pointer));
+        }
+    }
+}
+
+/// Estimates cost saved by accessing `array[iterator]` through pointer in one iteration.
+fn savings(commands: &[Command], array: &str, iterator: &str, weight: usize) -> usize {
+    let accessed = |identifier: &Identifier| matches!(identifier, Identifier::ArrayVar(a, i) if a == array && i == iterator);
+    let value = |value: &Value| match value {
+        Value::Identifier(identifier) if accessed(identifier) => READ_SAVING * weight,
+        _ => 0,
+    };
+    let condition = |condition: &Condition| -> usize {
+        condition
+            .comparisons()
+            .iter()
+            .map(|comparison| {
+                savings(&comparison.before, array, iterator, weight) + value(&comparison.left) + value(&comparison.right)
+            })
+            .sum()
+    };
+    let nested = weight * NESTED_LOOP_WEIGHT;
+
+    commands
+        .iter()
+        .map(|command| match command {
+            Command::Assign(identifier, operation) => {
+                let target = if accessed(identifier) {
+                    WRITE_SAVING * weight
+                } else {
+                    0
+                };
+                target + value(&operation.left) + value(&operation.right)
+            }
+            Command::Read(identifier) if accessed(identifier) => WRITE_SAVING * weight,
+            Command::Read(_)
+            | Command::FunctionCall(_, _)
+            | Command::Asm(_)
+            | Command::Break
+            | Command::Continue
+            | Command::Line(_) => 0,
+            Command::Write(written) => value(written),
+            Command::If(cond, commands) => {
+                condition(cond) + savings(commands, array, iterator, weight)
+            }
+            Command::IfElse(cond, commands1, commands2) => {
+                condition(cond)
+                    + savings(commands1, array, iterator, weight)
+                    + savings(commands2, array, iterator, weight)
+            }
+            Command::Case(checked, branches, otherwise) => {
+                value(checked)
+                    + branches
+                        .iter()
+                        .map(|branch| savings(&branch.commands, array, iterator, weight))
+                        .sum::<usize>()
+                    + savings(otherwise, array, iterator, weight)
+            }
+            Command::While(cond, commands) | Command::Repeat(cond, commands) => {
+                condition(cond) * NESTED_LOOP_WEIGHT + savings(commands, array, iterator, nested)
+            }
+            Command::For(_, start, end, step, commands) | Command::ForDown(_, start, end, step, commands) => {
+                value(start) + value(end) + value(step) + savings(commands, array, iterator, nested)
+            }
+        })
+        .sum()
+}
+
+#[test]
+fn steps_pointer_with_iterator() {
+    use crate::optimizer::pipeline::Options;
+    use crate::vm::compile;
+
+    let translator = compile(
+        "PROGRAM IS n, t[1:5] BEGIN READ n; FOR i FROM 1 TO n DO t[i] := i; WRITE t[i]; ENDFOR END",
+        Options::default(),
+    );
+
+    let lines = &translator.program.instructions;
+    let start = lines
+        .iter()
+        .position(|line| line.labels.iter().any(|label| label.contains("for start")))
+        .unwrap();
+    // Iterator is allocated after the array, in cell 16, and is no longer added to the array offset
+    assert!(!lines[start..]
+        .iter()
+        .any(|line| matches!(line.instruction, Instruction::Add(Pointer::Cell(16)))));
+    assert!(lines[start..].iter().any(|line| matches!(
+        line.instruction,
+        Instruction::Store(Pointer::IndirectCell(_))
+    )));
+}
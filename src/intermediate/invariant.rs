@@ -0,0 +1,386 @@
+use crate::intermediate::{Instruction, InstructionFactory, TranslationError};
+use crate::optimizer::pipeline::Pass;
+use crate::procedures::FunctionRepository;
+use crate::structure::{AsmLine, Command, Condition, Declaration, Identifier, Operation, Operator, Value};
+use crate::variables::{Pointer, Type, VariableDictionary};
+use std::collections::HashSet;
+
+/// Variables and arrays that can be modified by executing some commands.
+#[derive(Debug, Default)]
+pub struct Writes {
+    variables: HashSet<String>,
+    arrays: HashSet<String>,
+    reference_variables: bool,
+    reference_arrays: bool,
+    /// Some `ASM` block stores through a pointer, so anything can be modified.
+    indirect: bool,
+}
+
+impl Writes {
+    /// Writes of the commands and of the condition checked with them, if there is one.
+    pub fn of(condition: Option<&Condition>, commands: &[Command], variables: &VariableDictionary) -> Writes {
+        let mut writes = Writes::default();
+        if let Some(condition) = condition {
+            writes.add_condition(condition);
+        }
+        writes.add_commands(commands);
+
+        // Arguments passed by reference can point to the same place,
+        // so writing to one of them can change all the others
+        writes.reference_variables = writes
+            .variables
+            .iter()
+            .any(|name| variables.is_reference(name));
+        writes.reference_arrays = writes
+            .arrays
+            .iter()
+            .any(|name| variables.is_reference(name));
+        writes
+    }
+
+    fn add_commands(&mut self, commands: &[Command]) {
+        for command in commands {
+            match command {
+                Command::Assign(identifier, _) | Command::Read(identifier) => {
+                    self.add_identifier(identifier);
+                }
+                Command::If(condition, commands)
+                | Command::While(condition, commands)
+                | Command::Repeat(condition, commands) => {
+                    self.add_condition(condition);
+                    self.add_commands(commands);
+                }
+                Command::IfElse(condition, commands1, commands2) => {
+                    self.add_condition(condition);
+                    self.add_commands(commands1);
+                    self.add_commands(commands2);
+                }
+                Command::Case(_, branches, otherwise) => {
+                    for branch in branches {
+                        self.add_commands(&branch.commands);
+                    }
+                    self.add_commands(otherwise);
+                }
+                Command::For(iterator, _, _, _, commands)
+                | Command::ForDown(iterator, _, _, _, commands) => {
+                    self.variables.insert(iterator.clone());
+                    self.variables.insert(format!("{}_end", iterator));
+                    self.variables.insert(format!("{}_step", iterator));
+                    self.add_commands(commands);
+                }
+                Command::FunctionCall(_, arguments) => {
+                    // Procedures get arguments by reference and can modify any of them
+                    for argument in arguments {
+                        self.variables.insert(argument.clone());
+                        self.arrays.insert(argument.clone());
+                    }
+                }
+                Command::Write(_) | Command::Break | Command::Continue | Command::Line(_) => {}
+                Command::Asm(lines) => {
+                    for line in lines {
+                        if let Some(identifier) = line.identifier() {
+                            self.add_identifier(identifier);
+                        }
+                        self.indirect |= line.writes_indirectly();
+                    }
+                }
+            }
+        }
+    }
+
+    fn add_condition(&mut self, condition: &Condition) {
+        for comparison in condition.comparisons() {
+            self.add_commands(&comparison.before);
+        }
+    }
+
+    fn add_identifier(&mut self, identifier: &Identifier) {
+        match identifier {
+            Identifier::Variable(name) => {
+                self.variables.insert(name.clone());
+            }
+            Identifier::ArrayLit(name, _) | Identifier::ArrayVar(name, _) | Identifier::Element(name, _) => {
+                self.arrays.insert(name.clone());
+            }
+        }
+    }
+
+    pub fn writes_variable(&self, name: &str, variables: &VariableDictionary) -> bool {
+        self.indirect
+            || self.variables.contains(name)
+            || (self.reference_variables && variables.is_reference(name))
+    }
+
+    pub fn writes_array(&self, name: &str, variables: &VariableDictionary) -> bool {
+        self.indirect
+            || self.arrays.contains(name)
+            || (self.reference_arrays && variables.is_reference(name))
+    }
+
+    /// Whether the value stays the same during whole execution of the commands.
+    pub fn is_invariant(&self, value: &Value, variables: &VariableDictionary) -> bool {
+        match value {
+            Value::Literal(_) => true,
+            Value::Identifier(Identifier::Variable(name)) => !self.writes_variable(name, variables),
+            Value::Identifier(Identifier::ArrayLit(name, _)) => !self.writes_array(name, variables),
+            Value::Identifier(Identifier::ArrayVar(name, index)) => {
+                !self.writes_array(name, variables) && !self.writes_variable(index, variables)
+            }
+            Value::Identifier(Identifier::Element(name, indices)) => {
+                !self.writes_array(name, variables) && indices.iter().all(|index| self.is_invariant(index, variables))
+            }
+            // Nested operations are computed in scratch cells, which can not be kept during the loop
+            Value::Call(_, _) | Value::Expression(_) => false,
+        }
+    }
+}
+
+impl InstructionFactory {
+    /// Computes values that do not change inside the loop before entering it.
+    ///
+    /// Expressions with invariant operands are assigned to new variables and replaced in the loop body,
+    /// addresses of array elements with invariant indices are stored in cells used as pointers.
+    /// Returns number of hoisted addresses, that have to be released after translating the loop.
+    pub(crate) fn hoist_invariants(
+        &mut self,
+        condition: Option<&Condition>,
+        iterator: Option<&str>,
+        commands: &mut [Command],
+        variables: &mut VariableDictionary,
+        functions: &mut FunctionRepository,
+    ) -> Result<usize, TranslationError> {
+        if !self.options.is_enabled(Pass::Licm) {
+            return Ok(0);
+        }
+        let mut writes = Writes::of(condition, commands, variables);
+        if let Some(iterator) = iterator {
+            writes.variables.insert(iterator.to_string());
+        }
+        self.action_stack.push("Hoisted".to_string());
+
+        let mut expressions = vec![];
+        self.replace_invariant_expressions(commands, &writes, variables, &mut expressions);
+        for (name, operation) in expressions {
+            variables.add(Declaration::VariableDecl(name.clone()))?;
+            self.translate_assign(Identifier::Variable(name), operation, variables, functions)?;
+        }
+
+        let mut identifiers = vec![];
+        if let Some(condition) = condition {
+            collect_condition(condition, &mut identifiers);
+        }
+        collect_commands(commands, &mut identifiers);
+
+        let mut hoisted = 0;
+        let mut seen = HashSet::new();
+        // Pointers need static cells, which a recursive call would overwrite
+        if variables.frame().is_some() {
+            identifiers.clear();
+        }
+        for identifier in identifiers {
+            let Identifier::ArrayVar(array, index) = identifier else {
+                continue;
+            };
+            if writes.writes_variable(index, variables) || !seen.insert((array, index)) {
+                continue;
+            }
+            let Ok(Type::Array(offset, index)) =
+                variables.read(Value::Identifier(identifier.clone()))
+            else {
+                continue;
+            };
+            let name = self.reserve_variable("address");
+            variables.add(Declaration::VariableDecl(name.clone()))?;
+            let cell = match variables.write(Value::Identifier(Identifier::Variable(name)))? {
+                Type::Variable(Pointer::Cell(cell)) => cell,
+                _ => panic!("Hoisted address is not a cell"),
+            };
+
+            self.action_stack.push(format!(
+                "address of {}",
+                Value::Identifier(identifier.clone())
+            ));
+            self.load_address(offset, index);
+            self.push(Instruction::Store(Pointer::Cell(cell)));
+            self.action_stack.pop();
+
+            self.hoisted_addresses
+                .push((offset, index, Pointer::IndirectCell(cell)));
+            hoisted += 1;
+        }
+
+        self.action_stack.pop();
+        Ok(hoisted)
+    }
+
+    pub(crate) fn release_hoisted(&mut self, count: usize) {
+        let len = self.hoisted_addresses.len();
+        self.hoisted_addresses.truncate(len - count);
+    }
+
+    /// Replaces array accesses whose address was computed before the loop.
+    pub(crate) fn resolve_hoisted(&self, variable: Type) -> Type {
+        match variable {
+            Type::Array(offset, index) => self
+                .hoisted_addresses
+                .iter()
+                .rev()
+                .find(|(o, i, _)| *o == offset && *i == index)
+                .map_or(variable, |(_, _, pointer)| Type::Variable(*pointer)),
+            other => other,
+        }
+    }
+
+    fn replace_invariant_expressions(
+        &mut self,
+        commands: &mut [Command],
+        writes: &Writes,
+        variables: &VariableDictionary,
+        expressions: &mut Vec<(String, Operation)>,
+    ) {
+        for command in commands {
+            match command {
+                Command::Assign(_, operation) => {
+                    if operation.operator == Operator::Value
+                        || !writes.is_invariant(&operation.left, variables)
+                        || !writes.is_invariant(&operation.right, variables)
+                        || variables.read(operation.left.clone()).is_err()
+                        || variables.read(operation.right.clone()).is_err()
+                    {
+                        continue;
+                    }
+                    let key = format!("{:?}", operation);
+                    let name = match expressions
+                        .iter()
+                        .find(|(_, op)| format!("{:?}", op) == key)
+                    {
+                        Some((name, _)) => name.clone(),
+                        None => {
+                            let name = self.reserve_variable("invariant");
+                            expressions.push((name.clone(), operation.clone()));
+                            name
+                        }
+                    };
+                    *operation = Operation {
+                        operator: Operator::Value,
+                        left: Value::Identifier(Identifier::Variable(name)),
+                        right: Value::Literal(0),
+                    };
+                }
+                Command::If(_, commands)
+                | Command::While(_, commands)
+                | Command::Repeat(_, commands)
+                | Command::For(_, _, _, _, commands)
+                | Command::ForDown(_, _, _, _, commands) => {
+                    self.replace_invariant_expressions(commands, writes, variables, expressions);
+                }
+                Command::IfElse(_, commands1, commands2) => {
+                    self.replace_invariant_expressions(commands1, writes, variables, expressions);
+                    self.replace_invariant_expressions(commands2, writes, variables, expressions);
+                }
+                Command::Case(_, branches, otherwise) => {
+                    for branch in branches {
+                        self.replace_invariant_expressions(&mut branch.commands, writes, variables, expressions);
+                    }
+                    self.replace_invariant_expressions(otherwise, writes, variables, expressions);
+                }
+                Command::FunctionCall(_, _)
+                | Command::Read(_)
+                | Command::Write(_)
+                | Command::Asm(_)
+                | Command::Break
+                | Command::Continue
+                | Command::Line(_) => {}
+            }
+        }
+    }
+}
+
+fn collect_value<'a>(value: &'a Value, identifiers: &mut Vec<&'a Identifier>) {
+    match value {
+        Value::Identifier(identifier) => identifiers.push(identifier),
+        Value::Expression(operation) => {
+            collect_value(&operation.left, identifiers);
+            collect_value(&operation.right, identifiers);
+        }
+        Value::Literal(_) | Value::Call(_, _) => {}
+    }
+}
+
+fn collect_condition<'a>(condition: &'a Condition, identifiers: &mut Vec<&'a Identifier>) {
+    for comparison in condition.comparisons() {
+        collect_commands(&comparison.before, identifiers);
+        collect_value(&comparison.left, identifiers);
+        collect_value(&comparison.right, identifiers);
+    }
+}
+
+pub(crate) fn collect_commands<'a>(commands: &'a [Command], identifiers: &mut Vec<&'a Identifier>) {
+    for command in commands {
+        match command {
+            Command::Assign(identifier, operation) => {
+                identifiers.push(identifier);
+                collect_value(&operation.left, identifiers);
+                collect_value(&operation.right, identifiers);
+            }
+            Command::If(condition, commands)
+            | Command::While(condition, commands)
+            | Command::Repeat(condition, commands) => {
+                collect_condition(condition, identifiers);
+                collect_commands(commands, identifiers);
+            }
+            Command::IfElse(condition, commands1, commands2) => {
+                collect_condition(condition, identifiers);
+                collect_commands(commands1, identifiers);
+                collect_commands(commands2, identifiers);
+            }
+            Command::Case(value, branches, otherwise) => {
+                collect_value(value, identifiers);
+                for branch in branches {
+                    collect_commands(&branch.commands, identifiers);
+                }
+                collect_commands(otherwise, identifiers);
+            }
+            Command::For(_, start, end, step, commands) | Command::ForDown(_, start, end, step, commands) => {
+                collect_value(start, identifiers);
+                collect_value(end, identifiers);
+                collect_value(step, identifiers);
+                collect_commands(commands, identifiers);
+            }
+            Command::Read(identifier) => identifiers.push(identifier),
+            Command::Write(value) => collect_value(value, identifiers),
+            Command::Asm(lines) => identifiers.extend(lines.iter().filter_map(AsmLine::identifier)),
+            Command::FunctionCall(_, _) | Command::Break | Command::Continue | Command::Line(_) => {}
+        }
+    }
+}
+
+#[test]
+fn hoists_invariant_multiplication() {
+    use crate::optimizer::pipeline::Options;
+    use crate::vm::compile;
+
+    let translator = compile(
+        "PROGRAM IS a, b, x, y, t[0:3] BEGIN
+            READ a; READ b; x := 0;
+            WHILE x < 3 DO y := a * b; t[a] := y; x := x + 1; ENDWHILE
+        END",
+        Options::default(),
+    );
+
+    let lines = &translator.program.instructions;
+    let start = lines
+        .iter()
+        .position(|line| {
+            line.labels
+                .iter()
+                .any(|label| label.contains("while start"))
+        })
+        .unwrap();
+    // Multiplication arguments are prepared and address t[a] computed only before the loop,
+    // a is the first variable allocated after scratch cells
+    assert!(!lines[start..].iter().any(|line| matches!(
+        line.instruction,
+        Instruction::Store(Pointer::Cell(6)) | Instruction::Add(Pointer::Cell(10))
+    )));
+}
@@ -1,11 +1,18 @@
+mod assembler;
 mod assign;
+mod case;
 mod command;
 mod condition;
 pub mod program_translator;
 mod scanner;
 mod to_code;
 mod action_stack;
+mod tracker;
+mod invariant;
+mod induction;
+pub mod scratch;
 
+use crate::optimizer::pipeline::{Options, Pass};
 use crate::preprocessor::StaticAnalysisError;
 use crate::procedures::{DummyProcedure, FunctionRepository, ProcedureHandler};
 use crate::structure::Declaration::{ArrayDecl, VariableDecl};
@@ -13,6 +20,8 @@ use crate::structure::{Command, Identifier, Operation, Operator, Value};
 use crate::variables::{Pointer, Type, VariableDictionary, VariableError};
 use std::fmt::{Debug, Display, Formatter};
 use std::mem;
+use scratch::{ScratchAllocator, REMAINDER, RESULT, SCRATCH_END};
+use tracker::{Content, ValueTracker};
 
 pub struct InstructionLine {
     pub instruction: Instruction,
@@ -48,6 +57,8 @@ pub enum Instruction {
     GoZero(String),
     Return(Pointer),
     LoadKPlus3,
+    /// Loads address of the instruction with the label, replaced with loading a literal like `LoadKPlus3`.
+    LoadLabel(String),
     Halt,
 }
 pub struct InstructionFactory {
@@ -56,6 +67,12 @@ pub struct InstructionFactory {
     next_labels: Vec<String>,
     pub(crate) action_stack: Vec<String>,
     instruction_start: usize,
+    tracker: ValueTracker,
+    hoisted_addresses: Vec<(Pointer, Pointer, Pointer)>,
+    scratch: ScratchAllocator,
+    /// Labels of the next iteration and of the end of loops being translated, innermost last.
+    loops: Vec<(String, String)>,
+    pub(crate) options: Options,
 }
 
 pub enum TranslationError {
@@ -64,6 +81,7 @@ pub enum TranslationError {
     NegativeShift(String),
     NoFunction(String),
     ErrorWithLocation(String, Vec<String>),
+    Assembly(String),
 }
 
 impl Debug for TranslationError {
@@ -81,6 +99,9 @@ impl Debug for TranslationError {
             TranslationError::NoFunction(err) => {
                 write!(f, "There is no declared function with name: {:?}", err)
             }
+            TranslationError::Assembly(err) => {
+                write!(f, "Assembly error: {}", err)
+            }
             TranslationError::ErrorWithLocation(error, location) => {
                 write!(f, "{}\n", error)?;
                 write!(f, "Location: {}", location.join(" -> "))
@@ -109,10 +130,16 @@ impl InstructionFactory {
             action_stack: vec![name],
             next_labels: Vec::new(),
             instruction_start,
+            tracker: ValueTracker::new(),
+            hoisted_addresses: Vec::new(),
+            scratch: ScratchAllocator::default(),
+            loops: Vec::new(),
+            options: Options::default(),
         }
     }
 
     pub fn push(&mut self, instruction: Instruction) {
+        self.tracker.update(&instruction);
         let instruction = InstructionLine {
             instruction,
             comment: self.action_stack.join(" "),
@@ -134,30 +161,137 @@ impl InstructionFactory {
     }
 
     fn load(&mut self, variable: Type) {
-        match variable {
+        match self.resolve_hoisted(variable) {
             Type::Variable(pointer) => {
-                self.push(Instruction::Load(pointer));
+                if !self.known(|tracker| tracker.accumulator_holds(Content::Value(pointer))) {
+                    self.push(Instruction::Load(pointer));
+                }
             }
             Type::Array(pointer1, pointer2) => {
+                if self.known(|tracker| tracker.accumulator_holds(Content::Element(pointer1, pointer2))) {
+                    return;
+                }
+                let address = Content::Address(pointer1, pointer2);
+                if let Some(cell) = self.known(|tracker| tracker.find_scratch(address)) {
+                    self.push(Instruction::Load(Pointer::IndirectCell(cell)));
+                    return;
+                }
+                self.load_address(pointer1, pointer2);
+                self.push(Instruction::Load(Pointer::IndirectCell(0)));
+            }
+        }
+    }
+
+    /// Puts address of array element into accumulator.
+    fn load_address(&mut self, pointer1: Pointer, pointer2: Pointer) {
+        let address = Content::Address(pointer1, pointer2);
+        if self.known(|tracker| tracker.accumulator_holds(address)) {
+            return;
+        }
+        match self.known(|tracker| tracker.find_scratch(address)) {
+            Some(cell) => {
+                self.push(Instruction::Load(Pointer::Cell(cell)));
+            }
+            None => {
                 self.push(Instruction::Load(pointer1));
                 self.push(Instruction::Add(pointer2));
-                self.push(Instruction::Load(Pointer::IndirectCell(0)));
             }
         }
     }
 
-    pub fn prepare_pointer(&mut self, variable: Type, registry: usize) -> Pointer {
-        match variable {
+    /// Asks the tracker about values held in cells, answers nothing when skipping loads is disabled.
+    fn known<T: Default>(&self, question: impl Fn(&ValueTracker) -> T) -> T {
+        match self.options.is_enabled(Pass::Loads) {
+            true => question(&self.tracker),
+            false => T::default(),
+        }
+    }
+
+    /// Loads result of the operation, if it was already computed and is still held somewhere.
+    fn load_result(&mut self, operator: Operator, first: Type, second: Type) -> bool {
+        if !self.options.is_enabled(Pass::Cse) {
+            return false;
+        }
+        let Some(candidates) = self.result_contents(operator, first, second) else {
+            return false;
+        };
+        if candidates
+            .iter()
+            .any(|content| self.tracker.accumulator_holds(*content))
+        {
+            return true;
+        }
+        match candidates
+            .iter()
+            .find_map(|content| self.tracker.find_cell(*content))
+        {
+            Some(cell) => {
+                self.push(Instruction::Load(Pointer::Cell(cell)));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records that scratch cell holds result of the operation computed by a procedure.
+    fn remember_result(&mut self, cell: usize, operator: Operator, first: Type, second: Type) {
+        if let Some(candidates) = self.result_contents(operator, first, second) {
+            self.tracker.remember(cell, candidates[0]);
+        }
+    }
+
+    /// Division procedure leaves the quotient in cell 4 and the remainder in cell 2.
+    fn remember_division(&mut self, first: Type, second: Type) {
+        self.remember_result(RESULT, Operator::Divide, first, second);
+        self.remember_result(REMAINDER, Operator::Modulo, first, second);
+    }
+
+    /// Ways of describing result of the operation, if operands are not array elements.
+    fn result_contents(&self, operator: Operator, first: Type, second: Type) -> Option<Vec<Content>> {
+        let (Type::Variable(first), Type::Variable(second)) =
+            (self.resolve_hoisted(first), self.resolve_hoisted(second))
+        else {
+            return None;
+        };
+        Some(match operator {
+            Operator::Add => vec![
+                Content::Address(first, second),
+                Content::Address(second, first),
+            ],
+            Operator::Multiply => vec![
+                Content::Operation(operator, first, second),
+                Content::Operation(operator, second, first),
+            ],
+            _ => vec![Content::Operation(operator, first, second)],
+        })
+    }
+
+    /// Gives a pointer to the variable, address of array element is stored in a temporary
+    /// which has to be released with `release_pointer`.
+    pub fn prepare_pointer(&mut self, variable: Type) -> Pointer {
+        match self.resolve_hoisted(variable) {
             Type::Variable(pointer) => pointer,
             Type::Array(pointer1, pointer2) => {
-                self.push(Instruction::Load(pointer1));
-                self.push(Instruction::Add(pointer2));
-                self.push(Instruction::Store(Pointer::Cell(registry)));
-                Pointer::IndirectCell(registry)
+                let address = Content::Address(pointer1, pointer2);
+                let held: Vec<usize> = (1..SCRATCH_END)
+                    .filter(|cell| self.known(|tracker| tracker.scratch_holds(*cell, address)))
+                    .collect();
+                let cell = self.scratch.allocate(|cell| held.contains(&cell));
+                if !self.known(|tracker| tracker.scratch_holds(cell, address)) {
+                    self.load_address(pointer1, pointer2);
+                    self.push(Instruction::Store(Pointer::Cell(cell)));
+                }
+                Pointer::IndirectCell(cell)
             }
         }
     }
 
+    pub fn release_pointer(&mut self, pointer: Pointer) {
+        if let Pointer::IndirectCell(cell) = pointer {
+            self.scratch.release(cell);
+        }
+    }
+
     pub fn print(&self) {
         for line in &self.instructions {
             println!("{:?}", line);
@@ -165,16 +299,20 @@ impl InstructionFactory {
     }
 
     fn neg_mod2(&mut self) {
-        self.push(Instruction::Store(Pointer::Cell(2)));
+        let temporary = self.scratch.allocate(|_| false);
+        self.push(Instruction::Store(Pointer::Cell(temporary)));
         self.push(Instruction::Half);
         self.push(Instruction::Add(Pointer::Cell(0)));
-        self.push(Instruction::Subtr(Pointer::Cell(2)));
+        self.push(Instruction::Subtr(Pointer::Cell(temporary)));
+        self.scratch.release(temporary);
     }
 
     fn neg(&mut self) {
-        self.push(Instruction::Store(Pointer::Cell(2)));
-        self.push(Instruction::Subtr(Pointer::Cell(2)));
-        self.push(Instruction::Subtr(Pointer::Cell(2)));
+        let temporary = self.scratch.allocate(|_| false);
+        self.push(Instruction::Store(Pointer::Cell(temporary)));
+        self.push(Instruction::Subtr(Pointer::Cell(temporary)));
+        self.push(Instruction::Subtr(Pointer::Cell(temporary)));
+        self.scratch.release(temporary);
     }
 
     fn write(&mut self, value: Type) {
@@ -205,8 +343,11 @@ impl InstructionFactory {
                 }
             },
             _ => {
-                self.load(value);
+                // Value is read to the accumulator and stored under the address
+                let pointer = self.prepare_pointer(value);
                 self.push(Instruction::Get(Pointer::Cell(0)));
+                self.push(Instruction::Store(pointer));
+                self.release_pointer(pointer);
             }
         }
     }
@@ -222,7 +363,14 @@ impl InstructionFactory {
         label
     }
 
+    /// Creates unique name for variable used internally by the compiler.
+    pub fn reserve_variable(&mut self, name: &str) -> String {
+        self.label_counter += 1;
+        format!("@{}@{}", name, self.label_counter)
+    }
+
     pub fn set_label(&mut self, label: String) {
+        self.tracker.forget();
         self.next_labels.push(label);
     }
 
@@ -236,6 +384,7 @@ impl InstructionFactory {
         }
         self.instructions.append(&mut other.instructions);
         self.next_labels.append(&mut other.next_labels);
+        self.tracker.forget();
     }
 
     pub fn call_function(
@@ -252,7 +401,9 @@ impl InstructionFactory {
                 Ok(f)
             })?;
         mem::swap(fun, &mut dummy);
+        self.scratch.check_call(name, &dummy.clobbers());
         dummy.call(arguments, variables, self, functions)?;
+        self.tracker.forget();
         functions.insert(name.to_string(), dummy);
         Ok(())
     }
@@ -1,9 +1,18 @@
+use crate::intermediate::scratch::SCRATCH_END;
 use crate::intermediate::TranslationError::ErrorWithLocation;
 use crate::intermediate::{Instruction, InstructionFactory, TranslationError};
+use crate::constants::fold_constants;
+use crate::globals::pass_globals;
+use crate::lowering::lower_functions;
+use crate::optimizer::dead_code::eliminate_dead_code;
+use crate::optimizer::inline::decide_inlining;
+use crate::optimizer::pipeline::{Options, Pass};
+use crate::optimizer::unroll::unroll_program;
 use crate::preprocessor::Preprocessor;
 use crate::procedures::assembly::AssemblyProcedure;
 use crate::procedures::division::{division_procedure, DIVISION};
 use crate::procedures::multiplication::{multiplication_procedure, MULTIPLICATION};
+use crate::procedures::recursive::RecursiveProcedure;
 use crate::procedures::regular::RegularProcedure;
 use crate::procedures::{FunctionRepository, ProcedureHandler};
 use crate::structure::Program;
@@ -14,14 +23,23 @@ pub struct Translator {
     pub(crate) program: InstructionFactory,
     functions: FunctionRepository,
     memory_used: usize,
+    /// Cell of the stack pointer, allocated when some procedure is recursive.
+    pub(crate) stack: Option<usize>,
+    pub(crate) verbose: bool,
+    pub(crate) options: Options,
+    pub(crate) report: Vec<String>,
 }
 
 impl Translator {
     pub fn new() -> Self {
         Translator {
             program: InstructionFactory::new("alloc".to_string(), 0),
-            memory_used: 10,
+            memory_used: SCRATCH_END,
+            stack: None,
             functions: HashMap::new(),
+            verbose: false,
+            options: Options::default(),
+            report: vec![],
         }
     }
 
@@ -29,6 +47,9 @@ impl Translator {
         match self.translate(program) {
             Ok(_) => {
                 println!("Compilation successful!");
+                if self.verbose {
+                    self.report.iter().for_each(|line| println!("{}", line));
+                }
             }
             Err(error) => {
                 println!("Error happened during compilation:");
@@ -44,7 +65,19 @@ impl Translator {
 
         self.program.push(Instruction::Goto(literals.clone()));
 
+        fold_constants(&mut program)?;
+        self.report.extend(pass_globals(&mut program)?);
+        lower_functions(&mut program, &self.options)?;
+
+        if self.options.is_enabled(Pass::Unroll) {
+            unroll_program(&mut program);
+        }
+        if self.options.is_enabled(Pass::DeadCode) {
+            self.report.extend(eliminate_dead_code(&mut program));
+        }
+
         let mut preprocessor = Preprocessor::new();
+        preprocessor.shifts = self.options.is_enabled(Pass::Shifts);
         preprocessor
             .process_program(&mut program)?;
 
@@ -57,20 +90,24 @@ impl Translator {
         ];
 
         for (name, function) in defaults {
-            self.prepare_procedure(&preprocessor.function_counter, &name.to_string(), function)?;
+            self.prepare_procedure(&preprocessor, &name.to_string(), function)?;
         }
 
         for procedure in program.procedures {
             let name = procedure.name.clone();
 
-            let function: Box<dyn ProcedureHandler> = Box::new(RegularProcedure::new(procedure));
+            let function: Box<dyn ProcedureHandler> = match procedure.recursive {
+                true => Box::new(RecursiveProcedure::new(procedure, self.stack_pointer())),
+                false => Box::new(RegularProcedure::new(procedure)),
+            };
 
-            self.prepare_procedure(&preprocessor.function_counter, &name, function)?;
+            self.prepare_procedure(&preprocessor, &name, function)?;
         }
 
         let mut variables = VariableDictionary::new(self.memory_used);
         let mut intermediate =
             InstructionFactory::new("Main".to_string(), self.program.instructions.len());
+        intermediate.options = self.options;
         let main = self.program.reserve_label("main");
         intermediate.set_label(main.clone());
 
@@ -94,25 +131,45 @@ impl Translator {
         Ok(())
     }
 
-    fn prepare_procedure(&mut self, function_counter: &HashMap<String, usize>, name: &String, mut function: Box<dyn ProcedureHandler>) -> Result<(), TranslationError> {
-        match function_counter.get(name) {
-            None | Some(0) => {
-                //Do nothing
-            }
-            Some(1) => {
-                self.functions.insert(name.clone(), function);
-            }
-            _ => {
-                let (instructions, stack) = function.initialize(
-                    self.memory_used,
-                    self.program.instructions.len(),
-                    &mut self.functions,
-                )?;
-                self.program.merge(instructions);
-                self.memory_used = stack;
-                self.functions.insert(name.clone(), function);
+    fn stack_pointer(&mut self) -> usize {
+        match self.stack {
+            Some(cell) => cell,
+            None => {
+                self.stack = Some(self.memory_used);
+                self.memory_used += 1;
+                self.memory_used - 1
             }
         }
+    }
+
+    fn prepare_procedure(&mut self, preprocessor: &Preprocessor, name: &String, mut function: Box<dyn ProcedureHandler>) -> Result<(), TranslationError> {
+        let uses = preprocessor.function_counter.get(name).copied().unwrap_or(0);
+        if uses == 0 {
+            return Ok(());
+        }
+        let loop_uses = preprocessor.loop_uses.get(name).copied().unwrap_or(0);
+        let (inline, reason) = match self.options.is_enabled(Pass::Inline) {
+            true => decide_inlining(function.as_ref(), uses, loop_uses, self.options.size),
+            false => (false, "inlining disabled".to_string()),
+        };
+        self.report.push(format!(
+            "{} procedure {}: {}",
+            if inline { "Inlined" } else { "Not inlined" },
+            name,
+            reason
+        ));
+
+        if !inline {
+            let (instructions, stack) = function.initialize(
+                self.memory_used,
+                self.program.instructions.len(),
+                self.options,
+                &mut self.functions,
+            )?;
+            self.program.merge(instructions);
+            self.memory_used = stack;
+        }
+        self.functions.insert(name.clone(), function);
         Ok(())
     }
 }
@@ -9,7 +9,9 @@ impl Translator {
         let mut literals_map = HashMap::new();
         let mut labels_map = HashMap::new();
 
-        self.look_for_literals(&mut literals_map);
+        // Literals are placed after the code, so addresses of labels do not change
+        self.look_for_labels(&mut labels_map);
+        self.look_for_literals(&mut literals_map, &labels_map);
 
         let mut literals = vec![];
         for (literal, count) in literals_map.iter() {
@@ -22,6 +24,7 @@ impl Translator {
 
         self.allocate_literals(literals, variables, main_label, literal_label)?;
 
+        labels_map.clear();
         self.look_for_labels(&mut labels_map);
 
         self.swap_labels(variables, &mut labels_map)?;
@@ -91,7 +94,7 @@ impl Translator {
                     *instruction = Instruction::Jzero(count);
                 }
 
-                Instruction::LoadKPlus3 => {
+                Instruction::LoadKPlus3 | Instruction::LoadLabel(_) => {
                     panic!("LoadCurrentLocation should have been replaced by Load");
                 }
                 Instruction::Halt => {}
@@ -120,7 +123,7 @@ impl Translator {
         }
     }
 
-    fn look_for_literals(&mut self, literals_map: &mut HashMap<i64, usize>) {
+    fn look_for_literals(&mut self, literals_map: &mut HashMap<i64, usize>, labels_map: &HashMap<String, usize>) {
         for (
             num,
             InstructionLine {
@@ -162,6 +165,13 @@ impl Translator {
                     let entry = literals_map.entry(val).or_insert(0usize);
                     *entry += 1;
                 }
+                Instruction::LoadLabel(label) => {
+                    let val = *labels_map.get(label).unwrap() as i64;
+                    *comment = format!("{} LoadLabel @[{}]", comment, label);
+                    *instruction = Instruction::Load(Pointer::Literal(val));
+                    let entry = literals_map.entry(val).or_insert(0usize);
+                    *entry += 1;
+                }
                 Instruction::Halt => {}
                 Instruction::Set(_) => {}
             }
@@ -183,6 +193,13 @@ impl Translator {
             self.program.push(Instruction::Store(ptr));
             self.program.action_stack.pop();
         }
+        if let Some(stack) = self.stack {
+            // Frames of recursive procedures are placed after all variables
+            self.program.action_stack.push("stack".to_string());
+            self.program.push(Instruction::Set(variables.where_we_finished() as i64));
+            self.program.push(Instruction::Store(Pointer::Cell(stack)));
+            self.program.action_stack.pop();
+        }
         self.program.push(Instruction::Goto(main_label));
         Ok(())
     }
@@ -0,0 +1,88 @@
+//! Calling convention of the generated code.
+//!
+//! Cell 0 is the accumulator and cells `1..SCRATCH_END` are scratch cells,
+//! variables are allocated after them.
+//!
+//! - Temporaries holding addresses of array elements are handed out by `ScratchAllocator`
+//!   and live until the end of the statement which needed them.
+//! - Results of nested operations are kept in scratch cells not overwritten by built-in
+//!   procedures, when all of them are taken the result is spilled to a variable.
+//! - Built-in multiplication and division take arguments in `ARGUMENT_LEFT` and `ARGUMENT_RIGHT`,
+//!   leave the result in `RESULT` and the remainder in `REMAINDER`, and overwrite
+//!   the scratch cells their code stores to.
+//! - Procedures get arguments by reference, the address of every argument is stored
+//!   in a cell of the procedure, and can overwrite any scratch cell.
+//! - Return address of a procedure is stored in its own cell and used by `RTRN`.
+
+/// Cells 1..SCRATCH_END are used by the code generator as temporary registers.
+pub const SCRATCH_END: usize = 10;
+/// First argument of built-in multiplication and division.
+pub const ARGUMENT_LEFT: usize = 6;
+/// Second argument of built-in multiplication and division.
+pub const ARGUMENT_RIGHT: usize = 7;
+/// Product or quotient computed by built-in multiplication or division.
+pub const RESULT: usize = 4;
+/// Remainder computed by built-in division.
+pub const REMAINDER: usize = 2;
+
+/// Order in which temporaries are handed out, cells not used by built-in procedures first.
+const TEMPORARIES: [usize; SCRATCH_END - 1] = [1, 8, 9, 2, 3, 4, 5, 6, 7];
+
+/// Keeps track of scratch cells holding values which are still needed.
+#[derive(Debug, Default)]
+pub struct ScratchAllocator {
+    live: Vec<usize>,
+}
+
+impl ScratchAllocator {
+    /// Takes a free scratch cell, preferring one for which `holds` says it already holds the value.
+    pub fn allocate(&mut self, holds: impl Fn(usize) -> bool) -> usize {
+        let free: Vec<usize> = TEMPORARIES
+            .into_iter()
+            .filter(|cell| !self.live.contains(cell))
+            .collect();
+        let cell = free
+            .iter()
+            .copied()
+            .find(|cell| holds(*cell))
+            .or(free.first().copied())
+            .expect("All scratch cells are in use");
+        self.live.push(cell);
+        cell
+    }
+
+    /// Takes a free scratch cell which is not in `clobbered`, so it keeps its value during calls.
+    pub fn allocate_kept(&mut self, clobbered: &[usize]) -> Option<usize> {
+        let cell = TEMPORARIES
+            .into_iter()
+            .find(|cell| !self.live.contains(cell) && !clobbered.contains(cell))?;
+        self.live.push(cell);
+        Some(cell)
+    }
+
+    /// Frees the cell, does nothing for cells which are not allocated temporaries.
+    pub fn release(&mut self, cell: usize) {
+        self.live.retain(|live| *live != cell);
+    }
+
+    /// Checks that calling procedure `name`, which overwrites `clobbered` cells, keeps all temporaries.
+    pub fn check_call(&self, name: &str, clobbered: &[usize]) {
+        if let Some(cell) = self.live.iter().find(|cell| clobbered.contains(cell)) {
+            panic!("Call of {} overwrites temporary in cell {}", name, cell);
+        }
+    }
+}
+
+#[test]
+fn hands_out_cells_kept_by_calls_first() {
+    let mut scratch = ScratchAllocator::default();
+    let first = scratch.allocate(|_| false);
+    let second = scratch.allocate(|cell| cell == 3);
+    assert_eq!((first, second), (1, 3));
+    scratch.check_call("@multiplication", &[2, 4]);
+    scratch.release(second);
+    assert_eq!(scratch.allocate(|_| false), 8);
+    assert_eq!(scratch.allocate_kept(&[2, 3, 4, 5, 6, 7]), Some(9));
+    assert_eq!(scratch.allocate_kept(&[2, 3, 4, 5, 6, 7]), None);
+    assert!(std::panic::catch_unwind(|| scratch.check_call("procedure", &[1])).is_err());
+}
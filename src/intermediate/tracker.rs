@@ -0,0 +1,354 @@
+use crate::intermediate::scratch::SCRATCH_END;
+use crate::intermediate::Instruction;
+use crate::structure::Operator;
+use crate::variables::Pointer;
+
+/// Something a tracked cell is known to hold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Content {
+    /// Value stored under the pointer, literals are constants.
+    Value(Pointer),
+    /// Sum of values under both pointers, used as address of array element.
+    Address(Pointer, Pointer),
+    /// Array element stored under the address `first + second`.
+    Element(Pointer, Pointer),
+    /// Result of the operation on values under both pointers, sums are described by `Address`.
+    Operation(Operator, Pointer, Pointer),
+}
+
+/// Keeps track of what the accumulator (cell 0), scratch cells and variables currently hold,
+/// so that instructions recreating already present values can be skipped.
+///
+/// Knowledge is only valid for straight-line code, so it has to be forgotten
+/// whenever control can arrive from somewhere else (labels, returns from calls).
+#[derive(Debug, Default)]
+pub struct ValueTracker {
+    accumulator: Vec<Content>,
+    scratch: [Vec<Content>; SCRATCH_END],
+    /// Cells outside scratch with known content, other than their own value.
+    memory: Vec<(usize, Content)>,
+}
+
+fn is_scratch(cell: usize) -> bool {
+    cell > 0 && cell < SCRATCH_END
+}
+
+fn pointer_reads_cell(pointer: &Pointer, cell: usize) -> bool {
+    match pointer {
+        Pointer::Cell(c) | Pointer::IndirectCell(c) => *c == cell,
+        Pointer::Literal(_) => false,
+    }
+}
+
+fn pointer_reads_memory(pointer: &Pointer) -> bool {
+    match pointer {
+        Pointer::Cell(c) => !is_scratch(*c),
+        Pointer::IndirectCell(_) => true,
+        Pointer::Literal(_) => false,
+    }
+}
+
+impl Content {
+    /// Whether writing to `cell` can change the value described.
+    fn depends_on(&self, cell: usize) -> bool {
+        let memory = !is_scratch(cell);
+        match self {
+            Content::Value(pointer) => {
+                pointer_reads_cell(pointer, cell)
+                    || (memory && matches!(pointer, Pointer::IndirectCell(_)))
+            }
+            Content::Address(first, second) | Content::Operation(_, first, second) => {
+                pointer_reads_cell(first, cell)
+                    || pointer_reads_cell(second, cell)
+                    || (memory && matches!(first, Pointer::IndirectCell(_)))
+                    || (memory && matches!(second, Pointer::IndirectCell(_)))
+            }
+            Content::Element(first, second) => {
+                memory || pointer_reads_cell(first, cell) || pointer_reads_cell(second, cell)
+            }
+        }
+    }
+
+    /// Whether writing to unknown (non scratch) cell can change the value described.
+    fn depends_on_memory(&self) -> bool {
+        match self {
+            Content::Value(pointer) => pointer_reads_memory(pointer),
+            Content::Address(first, second) | Content::Operation(_, first, second) => {
+                pointer_reads_memory(first) || pointer_reads_memory(second)
+            }
+            Content::Element(_, _) => true,
+        }
+    }
+
+    fn uses_accumulator(&self) -> bool {
+        match self {
+            Content::Value(pointer) => pointer_reads_cell(pointer, 0),
+            Content::Address(first, second)
+            | Content::Element(first, second)
+            | Content::Operation(_, first, second) => {
+                pointer_reads_cell(first, 0) || pointer_reads_cell(second, 0)
+            }
+        }
+    }
+}
+
+impl ValueTracker {
+    pub fn new() -> Self {
+        ValueTracker::default()
+    }
+
+    /// Drops all knowledge, used when control flow can enter from elsewhere.
+    pub fn forget(&mut self) {
+        self.accumulator.clear();
+        self.scratch.iter_mut().for_each(Vec::clear);
+        self.memory.clear();
+    }
+
+    pub fn accumulator_holds(&self, content: Content) -> bool {
+        self.accumulator.contains(&content)
+    }
+
+    pub fn scratch_holds(&self, cell: usize, content: Content) -> bool {
+        is_scratch(cell) && self.scratch[cell].contains(&content)
+    }
+
+    /// Finds scratch cell that holds given content.
+    pub fn find_scratch(&self, content: Content) -> Option<usize> {
+        (1..SCRATCH_END).find(|cell| self.scratch[*cell].contains(&content))
+    }
+
+    /// Finds any cell that holds given content, preferring scratch cells.
+    pub fn find_cell(&self, content: Content) -> Option<usize> {
+        self.find_scratch(content).or_else(|| {
+            self.memory
+                .iter()
+                .find(|(_, known)| *known == content)
+                .map(|(cell, _)| *cell)
+        })
+    }
+
+    /// Records content of scratch cell left by code the tracker can not follow, like procedure calls.
+    pub fn remember(&mut self, cell: usize, content: Content) {
+        if is_scratch(cell) && !content.depends_on(cell) {
+            self.scratch[cell].push(content);
+        }
+    }
+
+    /// Updates knowledge with effects of the instruction.
+    pub fn update(&mut self, instruction: &Instruction) {
+        match instruction {
+            Instruction::Load(Pointer::Cell(0)) => {}
+            Instruction::Load(pointer) => {
+                let mut new = vec![];
+                match pointer {
+                    Pointer::Cell(cell) if is_scratch(*cell) => {
+                        new.extend(self.scratch[*cell].iter().copied());
+                    }
+                    Pointer::IndirectCell(0) => {
+                        for content in &self.accumulator {
+                            if let Content::Address(first, second) = content {
+                                new.push(Content::Element(*first, *second));
+                            }
+                        }
+                    }
+                    Pointer::IndirectCell(cell) if is_scratch(*cell) => {
+                        for content in &self.scratch[*cell] {
+                            if let Content::Address(first, second) = content {
+                                new.push(Content::Element(*first, *second));
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+                new.push(Content::Value(*pointer));
+                self.set_accumulator(new);
+            }
+            Instruction::Set(value) => {
+                self.set_accumulator(vec![Content::Value(Pointer::Literal(*value))]);
+            }
+            Instruction::Store(Pointer::Cell(0)) => {}
+            Instruction::Store(Pointer::Cell(cell)) => {
+                self.invalidate_cell(*cell);
+                if is_scratch(*cell) {
+                    self.scratch[*cell] = self.accumulator.clone();
+                } else {
+                    for content in &self.accumulator {
+                        self.memory.push((*cell, *content));
+                    }
+                }
+                self.accumulator.push(Content::Value(Pointer::Cell(*cell)));
+            }
+            Instruction::Store(Pointer::IndirectCell(cell)) => {
+                self.invalidate_memory();
+                let mut new = self.accumulator.clone();
+                if is_scratch(*cell) {
+                    for content in &self.scratch[*cell] {
+                        if let Content::Address(first, second) = content {
+                            new.push(Content::Element(*first, *second));
+                        }
+                    }
+                }
+                new.push(Content::Value(Pointer::IndirectCell(*cell)));
+                self.set_accumulator(new);
+            }
+            Instruction::Get(Pointer::Cell(0)) => {
+                self.accumulator.clear();
+            }
+            Instruction::Get(Pointer::Cell(cell)) => {
+                self.invalidate_cell(*cell);
+                if is_scratch(*cell) {
+                    self.scratch[*cell].clear();
+                }
+            }
+            Instruction::Get(_) => {
+                self.accumulator.clear();
+                self.invalidate_memory();
+            }
+            Instruction::Add(pointer) => {
+                let new = self
+                    .accumulator
+                    .iter()
+                    .filter_map(|content| match content {
+                        Content::Value(first) => Some(Content::Address(*first, *pointer)),
+                        _ => None,
+                    })
+                    .collect();
+                self.set_accumulator(new);
+            }
+            Instruction::Subtr(pointer) => {
+                let new = self
+                    .accumulator
+                    .iter()
+                    .filter_map(|content| match content {
+                        Content::Value(first) => {
+                            Some(Content::Operation(Operator::Subtract, *first, *pointer))
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                self.set_accumulator(new);
+            }
+            Instruction::Half | Instruction::LoadKPlus3 | Instruction::LoadLabel(_) => {
+                self.accumulator.clear();
+            }
+            Instruction::Return(_) => {
+                self.forget();
+            }
+            Instruction::Store(Pointer::Literal(_)) | Instruction::Put(_) | Instruction::Halt => {}
+            Instruction::Jump(_)
+            | Instruction::Jpos(_)
+            | Instruction::Jzero(_)
+            | Instruction::Jneg(_)
+            | Instruction::Goto(_)
+            | Instruction::GoPos(_)
+            | Instruction::GoNeg(_)
+            | Instruction::GoZero(_) => {}
+        }
+    }
+
+    fn set_accumulator(&mut self, contents: Vec<Content>) {
+        self.accumulator = contents
+            .into_iter()
+            .filter(|content| !content.uses_accumulator())
+            .collect();
+    }
+
+    fn invalidate_cell(&mut self, cell: usize) {
+        self.accumulator.retain(|content| !content.depends_on(cell));
+        for contents in self.scratch.iter_mut() {
+            contents.retain(|content| !content.depends_on(cell));
+        }
+        self.memory
+            .retain(|(known, content)| *known != cell && !content.depends_on(cell));
+    }
+
+    fn invalidate_memory(&mut self) {
+        self.accumulator
+            .retain(|content| !content.depends_on_memory());
+        for contents in self.scratch.iter_mut() {
+            contents.retain(|content| !content.depends_on_memory());
+        }
+        self.memory.clear();
+    }
+}
+
+#[test]
+fn forgets_scratch_cells_read_into() {
+    let mut tracker = ValueTracker::new();
+    tracker.update(&Instruction::Set(7));
+    tracker.update(&Instruction::Store(Pointer::Cell(1)));
+    assert!(tracker.scratch_holds(1, Content::Value(Pointer::Literal(7))));
+    tracker.update(&Instruction::Get(Pointer::Cell(1)));
+    assert!(!tracker.scratch_holds(1, Content::Value(Pointer::Literal(7))));
+    assert_eq!(tracker.find_scratch(Content::Value(Pointer::Literal(7))), None);
+}
+
+#[test]
+fn skips_reloading_known_values() {
+    use crate::intermediate::InstructionFactory;
+    use crate::procedures::FunctionRepository;
+    use crate::structure::{Command, Declaration, Identifier, Operation, Operator, Value};
+    use crate::variables::VariableDictionary;
+
+    let mut functions = FunctionRepository::new();
+    let mut variables = VariableDictionary::new(SCRATCH_END);
+    for name in ["a", "b", "c"] {
+        variables
+            .add(Declaration::VariableDecl(name.to_string()))
+            .unwrap();
+    }
+    let b = Value::Identifier(Identifier::Variable("b".to_string()));
+    let commands = vec![
+        Command::Read(Identifier::Variable("b".to_string())),
+        Command::Assign(
+            Identifier::Variable("a".to_string()),
+            Operation {
+                operator: Operator::Value,
+                left: Value::Identifier(Identifier::Variable("b".to_string())),
+                right: Value::Literal(0),
+            },
+        ),
+        Command::Assign(
+            Identifier::Variable("c".to_string()),
+            Operation {
+                operator: Operator::Add,
+                left: b,
+                right: Value::Literal(1),
+            },
+        ),
+    ];
+    let mut program = InstructionFactory::new("Tracker".to_string(), 0);
+    program
+        .translate_commands(commands, &mut variables, &mut functions)
+        .unwrap();
+
+    let b_cell = Pointer::Cell(SCRATCH_END + 1);
+    let loads = program
+        .instructions
+        .iter()
+        .filter(|line| matches!(line.instruction, Instruction::Load(pointer) if pointer == b_cell))
+        .count();
+    assert_eq!(loads, 1);
+}
+
+#[test]
+fn reuses_division_remainder() {
+    use crate::optimizer::pipeline::Options;
+    use crate::vm::compile;
+
+    let translator = compile(
+        "PROGRAM IS a, b, q, r BEGIN
+            READ a; READ b; r := a % b; q := a / b; WRITE q; WRITE r;
+        END",
+        Options::default(),
+    );
+
+    // Arguments are passed to the division procedure only once
+    let calls = translator
+        .program
+        .instructions
+        .iter()
+        .filter(|line| matches!(line.instruction, Instruction::Store(Pointer::Cell(6))))
+        .count();
+    assert_eq!(calls, 1);
+}
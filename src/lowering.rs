@@ -0,0 +1,762 @@
+use crate::optimizer::pipeline::Options;
+use crate::preprocessor::StaticAnalysisError;
+use crate::structure::{
+    ArgumentDecl, AsmLine, AsmOperand, Command, Comparison, Condition, ConditionOperator, Declaration, Identifier,
+    Operation, Operator, Program, Value,
+};
+use crate::variables::strides;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+/// Argument receiving result of a function, names given by the programmer can not contain `@`.
+pub const RESULT_ARGUMENT: &str = "@result";
+/// Written before the index when a checked index is outside bounds of the array, then the program halts.
+pub const BOUNDS_ERROR: i64 = -1_000_000_001;
+/// Written before the line of a checked division by zero, then the program halts.
+pub const DIVISION_ERROR: i64 = -1_000_000_002;
+/// Written before the line of a checked operation with result outside the bound, then the program halts.
+pub const OVERFLOW_ERROR: i64 = -1_000_000_003;
+
+/// Turns every `FUNCTION` into a procedure with one more argument receiving the result
+/// and every call used as a value into a call storing the result in a new variable,
+/// so the rest of the compiler only has to know about procedures.
+///
+/// Elements of matrices and elements with computed indices get the index computed before the command
+/// into a new variable, and matrices passed to procedures are followed by their strides.
+/// With `bounds_check` indices not checked at compile time are compared with bounds before the command,
+/// and arrays passed to procedures are followed also by their bounds. With `overflow_bound` every operation
+/// is computed before the command and checked, traps report lines of commands. Whatever is needed by
+/// comparisons after the first one of a condition is run right before the comparison instead.
+pub fn lower_functions(program: &mut Program, options: &Options) -> Result<(), StaticAnalysisError> {
+    let mut lowering = Lowering {
+        procedures: program
+            .procedures
+            .iter()
+            .filter(|procedure| procedure.returns.is_none())
+            .map(|procedure| procedure.name.clone())
+            .collect(),
+        functions: program
+            .procedures
+            .iter()
+            .filter(|procedure| procedure.returns.is_some())
+            .map(|procedure| procedure.name.clone())
+            .collect(),
+        signatures: program
+            .procedures
+            .iter()
+            .map(|procedure| (procedure.name.clone(), procedure.arguments.iter().map(dimensions).collect()))
+            .collect(),
+        bounds_check: options.bounds_check,
+        overflow_bound: options.overflow_bound,
+        lines: mem::take(&mut program.lines).into_iter(),
+        line: 0,
+        temporaries: 0,
+        checked: HashSet::new(),
+        declarations: vec![],
+        arrays: HashMap::new(),
+    };
+
+    for procedure in program.procedures.iter_mut() {
+        lowering.arrays = declared_arrays(&procedure.declarations);
+        let mut hidden = vec![];
+        for argument in procedure.arguments.iter() {
+            let (name, dimensions) = match argument {
+                ArgumentDecl::VariableArg(_) => continue,
+                ArgumentDecl::ArrayArg(name) => (name, 1),
+                ArgumentDecl::MatrixArg(name, dimensions) => (name, *dimensions),
+            };
+            let mut passed = |argument: String| {
+                hidden.push(ArgumentDecl::VariableArg(argument.clone()));
+                Value::Identifier(Identifier::Variable(argument))
+            };
+            let mut strides: Vec<Value> = (1..dimensions)
+                .map(|dimension| passed(format!("@stride{}@{}", dimension, name)))
+                .collect();
+            strides.push(Value::Literal(1));
+            let bounds = lowering.bounds_check.then(|| {
+                (1..=dimensions)
+                    .map(|dimension| {
+                        (
+                            passed(format!("@first{}@{}", dimension, name)),
+                            passed(format!("@last{}@{}", dimension, name)),
+                        )
+                    })
+                    .collect()
+            });
+            lowering.arrays.insert(name.clone(), Shape { strides, bounds });
+        }
+        procedure.arguments.append(&mut hidden);
+        lowering.lower_commands(&mut procedure.commands)?;
+        if let Some(mut value) = procedure.returns.take() {
+            procedure
+                .arguments
+                .push(ArgumentDecl::VariableArg(RESULT_ARGUMENT.to_string()));
+            // Returned value has no line of its own, its traps report the line of the last command
+            let mut calls = vec![];
+            lowering.lower_value(&mut value, &mut calls)?;
+            procedure.commands.append(&mut calls);
+            procedure.commands.push(Command::Assign(
+                Identifier::Variable(RESULT_ARGUMENT.to_string()),
+                Operation::of_value(value),
+            ));
+        }
+        procedure.declarations.append(&mut lowering.declarations);
+    }
+    lowering.arrays = declared_arrays(&program.declarations);
+    lowering.lower_commands(&mut program.commands)?;
+    program.declarations.append(&mut lowering.declarations);
+    Ok(())
+}
+
+struct Lowering {
+    procedures: HashSet<String>,
+    functions: HashSet<String>,
+    /// Number of dimensions of every argument of procedures, zero for variables.
+    signatures: HashMap<String, Vec<usize>>,
+    /// Whether indices are checked at runtime.
+    bounds_check: bool,
+    /// Largest absolute value of results of arithmetic when they are checked at runtime.
+    overflow_bound: Option<i64>,
+    /// Lines of commands not lowered yet, which are visited in the order they start in the source.
+    lines: std::vec::IntoIter<usize>,
+    /// Line of the command being lowered, reported by traps.
+    line: usize,
+    temporaries: usize,
+    /// Variables holding results of checked operations, which are known to be within the bound.
+    checked: HashSet<String>,
+    /// Variables for results created in the procedure being lowered.
+    declarations: Vec<Declaration>,
+    /// Arrays visible in the procedure being lowered.
+    arrays: HashMap<String, Shape>,
+}
+
+struct Shape {
+    /// Distance between consecutive indices of every dimension, arguments have them passed in variables.
+    strides: Vec<Value>,
+    /// First and last index of every dimension, arguments have them passed only when indices are checked.
+    bounds: Option<Vec<(Value, Value)>>,
+}
+
+fn dimensions(argument: &ArgumentDecl) -> usize {
+    match argument {
+        ArgumentDecl::VariableArg(_) => 0,
+        ArgumentDecl::ArrayArg(_) => 1,
+        ArgumentDecl::MatrixArg(_, dimensions) => *dimensions,
+    }
+}
+
+fn declared_arrays(declarations: &[Declaration]) -> HashMap<String, Shape> {
+    let shape = |bounds: &[(i64, i64)]| Shape {
+        strides: strides(bounds).into_iter().map(Value::Literal).collect(),
+        bounds: Some(
+            bounds
+                .iter()
+                .map(|(first, last)| (Value::Literal(*first), Value::Literal(*last)))
+                .collect(),
+        ),
+    };
+    declarations
+        .iter()
+        .filter_map(|declaration| match declaration {
+            Declaration::ArrayDecl(name, first, last) => Some((name.clone(), shape(&[(*first, *last)]))),
+            Declaration::MatrixDecl(name, bounds) => Some((name.clone(), shape(bounds))),
+            _ => None,
+        })
+        .collect()
+}
+
+impl Lowering {
+    fn lower_commands(&mut self, commands: &mut Vec<Command>) -> Result<(), StaticAnalysisError> {
+        for command in mem::take(commands) {
+            // Calls whose results are used by the command
+            let mut calls = vec![];
+            self.line = self.lines.next().unwrap_or(0);
+            let command = match command {
+                Command::Assign(mut identifier, operation) if self.overflow_bound.is_some() => {
+                    // Checked result is computed into a new variable first
+                    let mut value = Value::Expression(Box::new(operation));
+                    self.lower_value(&mut value, &mut calls)?;
+                    self.lower_identifier(&mut identifier, &mut calls)?;
+                    Command::Assign(identifier, Operation::of_value(value))
+                }
+                Command::Assign(mut identifier, mut operation) => {
+                    self.lower_value(&mut operation.left, &mut calls)?;
+                    self.lower_value(&mut operation.right, &mut calls)?;
+                    self.lower_identifier(&mut identifier, &mut calls)?;
+                    Command::Assign(identifier, operation)
+                }
+                Command::If(mut condition, mut body) => {
+                    self.lower_condition(&mut condition, &mut calls)?;
+                    self.lower_commands(&mut body)?;
+                    Command::If(condition, body)
+                }
+                Command::IfElse(mut condition, mut body, mut otherwise) => {
+                    self.lower_condition(&mut condition, &mut calls)?;
+                    self.lower_commands(&mut body)?;
+                    self.lower_commands(&mut otherwise)?;
+                    Command::IfElse(condition, body, otherwise)
+                }
+                Command::Case(mut value, mut branches, mut otherwise) => {
+                    self.lower_value(&mut value, &mut calls)?;
+                    for branch in branches.iter_mut() {
+                        self.lower_commands(&mut branch.commands)?;
+                    }
+                    self.lower_commands(&mut otherwise)?;
+                    Command::Case(value, branches, otherwise)
+                }
+                Command::While(mut condition, mut body) => {
+                    self.lower_condition(&mut condition, &mut calls)?;
+                    self.lower_commands(&mut body)?;
+                    // Condition is checked again after every iteration
+                    before_continue(&mut body, &calls);
+                    body.extend(calls.iter().cloned());
+                    Command::While(condition, body)
+                }
+                Command::Repeat(mut condition, mut body) => {
+                    let line = self.line;
+                    self.lower_commands(&mut body)?;
+                    self.line = line;
+                    let mut checks = vec![];
+                    self.lower_condition(&mut condition, &mut checks)?;
+                    before_continue(&mut body, &checks);
+                    body.append(&mut checks);
+                    Command::Repeat(condition, body)
+                }
+                Command::For(iterator, mut start, mut end, mut step, mut body) => {
+                    self.lower_value(&mut start, &mut calls)?;
+                    self.lower_value(&mut end, &mut calls)?;
+                    self.lower_value(&mut step, &mut calls)?;
+                    self.lower_commands(&mut body)?;
+                    Command::For(iterator, start, end, step, body)
+                }
+                Command::ForDown(iterator, mut start, mut end, mut step, mut body) => {
+                    self.lower_value(&mut start, &mut calls)?;
+                    self.lower_value(&mut end, &mut calls)?;
+                    self.lower_value(&mut step, &mut calls)?;
+                    self.lower_commands(&mut body)?;
+                    Command::ForDown(iterator, start, end, step, body)
+                }
+                Command::FunctionCall(name, mut arguments) => {
+                    // Result of a function called as a procedure is dropped
+                    self.lower_arguments(&name, &mut arguments, &mut calls)?;
+                    if self.functions.contains(&name) {
+                        arguments.push(self.temporary());
+                    }
+                    Command::FunctionCall(name, arguments)
+                }
+                Command::Write(mut value) => {
+                    self.lower_value(&mut value, &mut calls)?;
+                    Command::Write(value)
+                }
+                Command::Read(mut identifier) => {
+                    self.lower_identifier(&mut identifier, &mut calls)?;
+                    Command::Read(identifier)
+                }
+                Command::Asm(mut lines) => {
+                    for line in lines.iter_mut() {
+                        if let AsmLine::Instruction(_, AsmOperand::Identifier(identifier)) = line {
+                            self.lower_identifier(identifier, &mut calls)?;
+                        }
+                    }
+                    Command::Asm(lines)
+                }
+                command @ (Command::Break | Command::Continue | Command::Line(_)) => command,
+            };
+            commands.append(&mut calls);
+            commands.push(command);
+        }
+        Ok(())
+    }
+
+    /// Calls and checks of the first comparison go before the command, since it is always checked,
+    /// the others run only when short-circuit evaluation reaches their comparison.
+    fn lower_condition(
+        &mut self,
+        condition: &mut Condition,
+        calls: &mut Vec<Command>,
+    ) -> Result<(), StaticAnalysisError> {
+        for (position, comparison) in condition.comparisons_mut().into_iter().enumerate() {
+            let mut before = vec![];
+            self.lower_value(&mut comparison.left, &mut before)?;
+            self.lower_value(&mut comparison.right, &mut before)?;
+            match position {
+                0 => calls.append(&mut before),
+                _ => comparison.before = before,
+            }
+        }
+        Ok(())
+    }
+
+    fn lower_value(&mut self, value: &mut Value, calls: &mut Vec<Command>) -> Result<(), StaticAnalysisError> {
+        match value {
+            Value::Call(name, arguments) => {
+                if self.procedures.contains(name) {
+                    return Err(StaticAnalysisError::NoReturnValue(name.clone()));
+                }
+                let result = self.temporary();
+                let mut arguments = mem::take(arguments);
+                self.lower_arguments(name, &mut arguments, calls)?;
+                arguments.push(result.clone());
+                calls.push(Command::FunctionCall(mem::take(name), arguments));
+                *value = Value::Identifier(Identifier::Variable(result));
+            }
+            Value::Expression(operation) => {
+                self.lower_value(&mut operation.left, calls)?;
+                self.lower_value(&mut operation.right, calls)?;
+                if let Some(bound) = self.overflow_bound {
+                    self.check_operation(value, bound, calls);
+                }
+            }
+            Value::Identifier(identifier) => self.lower_identifier(identifier, calls)?,
+            Value::Literal(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Replaces element of a matrix or with computed index by element with index kept in a variable,
+    /// computing it before the command, and checks indices when it is enabled.
+    fn lower_identifier(
+        &mut self,
+        identifier: &mut Identifier,
+        calls: &mut Vec<Command>,
+    ) -> Result<(), StaticAnalysisError> {
+        let (name, mut indices) = match mem::replace(identifier, Identifier::Variable(String::new())) {
+            Identifier::Element(name, indices) => (name, indices),
+            Identifier::ArrayLit(name, index) => (name, vec![Value::Literal(index)]),
+            Identifier::ArrayVar(name, index) => (name, vec![Value::Identifier(Identifier::Variable(index))]),
+            variable => {
+                *identifier = variable;
+                return Ok(());
+            }
+        };
+        for index in indices.iter_mut() {
+            self.lower_value(index, calls)?;
+        }
+        let (strides, bounds) = match self.arrays.get(&name) {
+            Some(shape) => (shape.strides.clone(), shape.bounds.clone()),
+            None => (vec![Value::Literal(1)], None),
+        };
+        if strides.len() != indices.len() {
+            return Err(StaticAnalysisError::Dimensions(name));
+        }
+        for (index, (first, last)) in indices.iter_mut().zip(bounds.iter().flatten()) {
+            match (&*index, first, last) {
+                // Literal index of an array with one dimension is checked during translation
+                (Value::Literal(_), Value::Literal(_), Value::Literal(_)) if strides.len() == 1 => {}
+                (Value::Literal(index), Value::Literal(first), Value::Literal(last))
+                    if index < first || index > last =>
+                {
+                    return Err(StaticAnalysisError::InvalidIndex(name, *index));
+                }
+                (Value::Literal(_), Value::Literal(_), Value::Literal(_)) => {}
+                _ if self.bounds_check => {
+                    if !matches!(index, Value::Literal(_) | Value::Identifier(Identifier::Variable(_))) {
+                        self.compute_before(index, calls);
+                    }
+                    calls.push(Command::If(
+                        outside(index.clone(), first.clone(), last.clone()),
+                        trap(BOUNDS_ERROR, index.clone()),
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        // Literal parts are summed at compile time, multiplying by a power of two becomes a shift later
+        let mut offset = 0;
+        let mut terms = vec![];
+        for (index, stride) in indices.into_iter().zip(strides) {
+            match (index, stride) {
+                (Value::Literal(index), Value::Literal(stride)) => offset += index * stride,
+                (index, Value::Literal(1)) => terms.push(index),
+                (index, stride) => terms.push(Value::operation(Operator::Multiply, index, stride)),
+            }
+        }
+        *identifier = match terms.into_iter().reduce(|sum, term| Value::operation(Operator::Add, sum, term)) {
+            None => Identifier::ArrayLit(name, offset),
+            Some(Value::Identifier(Identifier::Variable(index))) if offset == 0 => Identifier::ArrayVar(name, index),
+            Some(index) => {
+                let index = match offset {
+                    0 => index,
+                    offset if offset < 0 => Value::operation(Operator::Subtract, index, Value::Literal(-offset)),
+                    offset => Value::operation(Operator::Add, index, Value::Literal(offset)),
+                };
+                let temporary = self.temporary();
+                calls.push(Command::Assign(Identifier::Variable(temporary.clone()), Operation::of_value(index)));
+                Identifier::ArrayVar(name, temporary)
+            }
+        };
+        Ok(())
+    }
+
+    /// Appends strides of matrices passed to the procedure and bounds of arrays when indices are checked,
+    /// checking their number of dimensions.
+    fn lower_arguments(
+        &mut self,
+        name: &str,
+        arguments: &mut Vec<String>,
+        calls: &mut Vec<Command>,
+    ) -> Result<(), StaticAnalysisError> {
+        let Some(signature) = self.signatures.get(name) else {
+            return Ok(());
+        };
+        let mut passed = vec![];
+        for (argument, dimensions) in arguments.iter().zip(signature) {
+            match (self.arrays.get(argument), dimensions) {
+                (Some(shape), 0) if shape.strides.len() > 1 => {
+                    return Err(StaticAnalysisError::Dimensions(argument.clone()));
+                }
+                (_, 0) => {}
+                (Some(shape), dimensions) if shape.strides.len() == *dimensions => {
+                    passed.extend(shape.strides[..dimensions - 1].iter().cloned());
+                    if self.bounds_check {
+                        for (first, last) in shape.bounds.iter().flatten() {
+                            passed.extend([first.clone(), last.clone()]);
+                        }
+                    }
+                }
+                (None, 1) if !self.bounds_check => {}
+                _ => return Err(StaticAnalysisError::Dimensions(argument.clone())),
+            }
+        }
+        for value in passed {
+            match value {
+                Value::Identifier(Identifier::Variable(value)) => arguments.push(value),
+                value => {
+                    let temporary = self.temporary();
+                    calls.push(Command::Assign(Identifier::Variable(temporary.clone()), Operation::of_value(value)));
+                    arguments.push(temporary);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Traps division by zero and operands or results of other operations outside the bound,
+    /// which are computed before the command into a new variable to be checked.
+    fn check_operation(&mut self, value: &mut Value, bound: i64, calls: &mut Vec<Command>) {
+        let Value::Expression(operation) = value else {
+            return;
+        };
+        let line = Value::Literal(self.line as i64);
+        match operation.operator {
+            Operator::Divide | Operator::Modulo => match &mut operation.right {
+                Value::Literal(0) => calls.extend(trap(DIVISION_ERROR, line.clone())),
+                Value::Literal(_) => {}
+                divisor => {
+                    if let Value::Expression(_) = divisor {
+                        self.compute_before(divisor, calls);
+                    }
+                    let zero = Comparison::new(ConditionOperator::Equal, divisor.clone(), Value::Literal(0));
+                    calls.push(Command::If(Condition::Compare(zero), trap(DIVISION_ERROR, line)));
+                }
+            },
+            Operator::Add | Operator::Subtract | Operator::Multiply => {
+                // Operands within the bound can not make the operation overflow
+                self.check_operand(&mut operation.left, bound, &line, calls);
+                self.check_operand(&mut operation.right, bound, &line, calls);
+                self.compute_before(value, calls);
+                if let Value::Identifier(Identifier::Variable(name)) = value {
+                    self.checked.insert(name.clone());
+                }
+                calls.push(Command::If(
+                    outside(value.clone(), Value::Literal(-bound), Value::Literal(bound)),
+                    trap(OVERFLOW_ERROR, line),
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    /// Traps the operand outside the bound, unless it is the result of a checked operation.
+    fn check_operand(&mut self, operand: &mut Value, bound: i64, line: &Value, calls: &mut Vec<Command>) {
+        match operand {
+            Value::Literal(literal) if literal.unsigned_abs() <= bound.unsigned_abs() => {}
+            Value::Literal(_) => calls.extend(trap(OVERFLOW_ERROR, line.clone())),
+            Value::Identifier(Identifier::Variable(name)) if self.checked.contains(name) => {}
+            _ => {
+                if let Value::Expression(_) = operand {
+                    self.compute_before(operand, calls);
+                }
+                calls.push(Command::If(
+                    outside(operand.clone(), Value::Literal(-bound), Value::Literal(bound)),
+                    trap(OVERFLOW_ERROR, line.clone()),
+                ));
+            }
+        }
+    }
+
+    /// Replaces the value by a new variable assigned before the command.
+    fn compute_before(&mut self, value: &mut Value, calls: &mut Vec<Command>) {
+        let temporary = self.temporary();
+        let value = mem::replace(value, Value::Identifier(Identifier::Variable(temporary.clone())));
+        calls.push(Command::Assign(Identifier::Variable(temporary), Operation::of_value(value)));
+    }
+
+    fn temporary(&mut self) -> String {
+        let name = format!("@value{}", self.temporaries);
+        self.temporaries += 1;
+        self.declarations
+            .push(Declaration::VariableDecl(name.clone()));
+        name
+    }
+}
+
+/// Whether the value is lower than the first or greater than the last bound.
+fn outside(value: Value, first: Value, last: Value) -> Condition {
+    let compare = |operator, right| {
+        Box::new(Condition::Compare(Comparison::new(operator, value.clone(), right)))
+    };
+    Condition::Or(compare(ConditionOperator::Lesser, first), compare(ConditionOperator::Greater, last))
+}
+
+/// Writes the error code and the value telling where it happened, then halts.
+fn trap(error: i64, value: Value) -> Vec<Command> {
+    vec![
+        Command::Write(Value::Literal(error)),
+        Command::Write(value),
+        Command::Asm(vec![AsmLine::instruction("HALT", AsmOperand::None)]),
+    ]
+}
+
+/// Puts the calls before every `CONTINUE` of the loop, which skips the end of the body.
+fn before_continue(commands: &mut Vec<Command>, calls: &[Command]) {
+    if calls.is_empty() {
+        return;
+    }
+    for command in mem::take(commands) {
+        match command {
+            Command::Continue => {
+                commands.extend(calls.iter().cloned());
+                commands.push(Command::Continue);
+            }
+            Command::If(condition, mut body) => {
+                before_continue(&mut body, calls);
+                commands.push(Command::If(condition, body));
+            }
+            Command::IfElse(condition, mut body, mut otherwise) => {
+                before_continue(&mut body, calls);
+                before_continue(&mut otherwise, calls);
+                commands.push(Command::IfElse(condition, body, otherwise));
+            }
+            Command::Case(value, mut branches, mut otherwise) => {
+                for branch in branches.iter_mut() {
+                    before_continue(&mut branch.commands, calls);
+                }
+                before_continue(&mut otherwise, calls);
+                commands.push(Command::Case(value, branches, otherwise));
+            }
+            command => commands.push(command),
+        }
+    }
+}
+
+#[test]
+fn passes_results_of_functions_through_variables() {
+    use crate::vm::compile_and_run;
+
+    let program = "FUNCTION square(n) IS r BEGIN r := n * n; RETURN r; END
+        NOINLINE FUNCTION next(n) IS r BEGIN r := n + 1; RETURN r; END
+        RECURSIVE FUNCTION sum(n) IS m, s BEGIN
+            s := 0;
+            IF n > 0 THEN m := n - 1; s := sum(m) + n; ENDIF
+            RETURN s;
+        END
+        PROGRAM IS n, i BEGIN
+            READ n;
+            WRITE square(n);
+            i := 0;
+            WHILE next(i) <= n DO i := next(i); ENDWHILE
+            WRITE i;
+            IF sum(n) = 15 THEN WRITE 1; ELSE WRITE 0; ENDIF
+        END";
+    for level in ["0", "2"] {
+        assert_eq!(compile_and_run(program, level, &[5]), vec![25, 5, 1]);
+    }
+}
+
+#[test]
+fn flattens_elements_of_matrices() {
+    use crate::vm::compile_and_run;
+
+    let program = "PROCEDURE fill(T a[,], n) IS BEGIN
+            FOR i FROM 0 TO n DO FOR j FROM 1 TO 5 DO a[i, j] := i * 10; a[i, j] := a[i, j] + j; ENDFOR ENDFOR
+        END
+        RECURSIVE PROCEDURE clear(T a[,], r) IS q BEGIN
+            IF r >= 0 THEN a[r, 1] := 0; q := r - 1; clear(a, q); ENDIF
+        END
+        PROGRAM IS t[0:3, 1:5], u[1:2, 0:7, 2:3], n, x, y BEGIN
+            READ n;
+            fill(t, n);
+            clear(t, n);
+            x := 2; y := 3;
+            WRITE t[x, y];
+            WRITE t[3, 1];
+            FOR i FROM 0 TO 7 DO u[2, i, 3] := i; ENDFOR
+            u[1, 0, 2] := 9;
+            WRITE u[2, n, 3];
+            WRITE u[1, 0, 2];
+        END";
+    for level in ["0", "2"] {
+        assert_eq!(compile_and_run(program, level, &[3]), vec![23, 0, 3, 9]);
+    }
+}
+
+#[test]
+fn computes_indices_of_elements() {
+    use crate::vm::compile_and_run;
+
+    let program = "PROGRAM IS t[0:9], u[0:9], m[0:3, 0:3], n, k BEGIN
+            READ n;
+            FOR i FROM 0 TO 9 DO t[i] := i * i; u[i] := 9 - i; ENDFOR
+            WRITE t[n + 1];
+            WRITE t[u[u[n]]];
+            k := 0;
+            WHILE t[k + 1] < 20 DO k := k + 1; ENDWHILE
+            WRITE k;
+            m[n - 1, u[8] + 1] := 55;
+            WRITE m[2, 2];
+            READ t[n * 2];
+            WRITE t[6];
+        END";
+    for level in ["0", "2"] {
+        assert_eq!(compile_and_run(program, level, &[3, 77]), vec![16, 9, 4, 55, 77]);
+    }
+}
+
+#[test]
+fn checks_bounds_of_arrays() {
+    use crate::optimizer::pipeline::Options;
+    use crate::vm::{compile, run};
+
+    let program = "PROCEDURE put(T a[,], i, j) IS BEGIN a[i, j] := i + j; END
+        PROGRAM IS t[0:2, 1:3], u[2:5], i, j BEGIN
+            READ i; READ j;
+            put(t, i, j);
+            WRITE t[i, j];
+            u[i + j] := 1;
+            WRITE 42;
+        END";
+    let cases = [
+        ([1, 3], vec![4, 42]),
+        ([3, 1], vec![BOUNDS_ERROR, 3]),
+        ([2, 4], vec![BOUNDS_ERROR, 4]),
+        ([0, 1], vec![1, BOUNDS_ERROR, 1]),
+    ];
+    for level in ["0", "2"] {
+        let mut options = Options::level(level).unwrap();
+        options.bounds_check = true;
+        let code = compile(program, options).to_code(false);
+        for (input, output) in cases.iter() {
+            let execution = run(&code, input, 1_000_000).unwrap();
+            assert_eq!(&execution.output, output, "input {:?}", input);
+        }
+    }
+
+    // Index guarded by the first comparison is checked only when the guard holds
+    let program = "PROGRAM IS t[1:3], i BEGIN
+            t[1] := 5; t[2] := 5; t[3] := 5;
+            READ i;
+            WHILE i <= 3 AND t[i] = 5 DO i := i + 1; ENDWHILE
+            WRITE i;
+        END";
+    let cases = [([1], vec![4]), ([0], vec![BOUNDS_ERROR, 0])];
+    for level in ["0", "2"] {
+        let mut options = Options::level(level).unwrap();
+        options.bounds_check = true;
+        let code = compile(program, options).to_code(false);
+        for (input, output) in cases.iter() {
+            let execution = run(&code, input, 1_000_000).unwrap();
+            assert_eq!(&execution.output, output, "input {:?}", input);
+        }
+    }
+}
+
+#[test]
+fn traps_division_by_zero_and_overflow() {
+    use crate::optimizer::pipeline::{Options, OVERFLOW_BOUND};
+    use crate::vm::{compile, run};
+
+    let program = "PROGRAM IS x, y BEGIN
+            READ x; READ y;
+            WRITE x / y;
+            WHILE x < 1000 DO
+                x := x * y + 1;
+            ENDWHILE
+            WRITE x % (y - 2);
+        END";
+    let cases = [
+        ([5, 4], vec![1, 1]),
+        ([5, 0], vec![DIVISION_ERROR, 3]),
+        ([5, 2], vec![2, DIVISION_ERROR, 7]),
+        ([5, 100], vec![0, OVERFLOW_ERROR, 5]),
+    ];
+    for level in ["0", "2"] {
+        let mut options = Options::level(level).unwrap();
+        options.overflow_bound = Some(10000);
+        let code = compile(program, options).to_code(false);
+        for (input, output) in cases.iter() {
+            let execution = run(&code, input, 1_000_000).unwrap();
+            assert_eq!(&execution.output, output, "input {:?}", input);
+        }
+    }
+
+    // Operands are checked, product of values outside the bound could wrap to a small result
+    let program = "PROGRAM IS x, y, z BEGIN
+            READ x; READ y; z := x * y; WRITE z;
+            WRITE (x / 2) + y;
+        END";
+    let cases = [
+        ([4294967296, 4294967296], vec![OVERFLOW_ERROR, 2]),
+        ([-6, 7], vec![-42, 4]),
+        ([9000000000, 0], vec![OVERFLOW_ERROR, 2]),
+    ];
+    for level in ["0", "2"] {
+        let mut options = Options::level(level).unwrap();
+        options.overflow_bound = Some(OVERFLOW_BOUND);
+        let code = compile(program, options).to_code(false);
+        for (input, output) in cases.iter() {
+            let execution = run(&code, input, 1_000_000).unwrap();
+            assert_eq!(&execution.output, output, "input {:?}", input);
+        }
+    }
+
+    // Division guarded by the first comparison is checked only when the guard holds
+    let program = "PROGRAM IS x, y BEGIN
+            READ x; READ y;
+            IF y != 0 AND x / y > 2 THEN WRITE 1; ELSE WRITE 0; ENDIF
+            IF y = 0 OR x * y < 100 THEN WRITE 2; ENDIF
+        END";
+    let cases = [
+        ([7, 0], vec![0, 2]),
+        ([7, 2], vec![1, 2]),
+        ([4000000000, 1], vec![1, OVERFLOW_ERROR, 4]),
+    ];
+    for level in ["0", "2"] {
+        let mut options = Options::level(level).unwrap();
+        options.overflow_bound = Some(OVERFLOW_BOUND);
+        let code = compile(program, options).to_code(false);
+        for (input, output) in cases.iter() {
+            let execution = run(&code, input, 1_000_000).unwrap();
+            assert_eq!(&execution.output, output, "input {:?}", input);
+        }
+    }
+
+    // Returned value of a function is not a command, lines after it are kept
+    let program = "FUNCTION half(n) IS r BEGIN
+            r := n / 2;
+            RETURN r;
+        END
+        PROGRAM IS x, y BEGIN
+            READ x; READ y;
+            WRITE half(x);
+            WRITE x / y;
+        END";
+    for level in ["0", "2"] {
+        let mut options = Options::level(level).unwrap();
+        options.overflow_bound = Some(OVERFLOW_BOUND);
+        let code = compile(program, options).to_code(false);
+        let execution = run(&code, &[6, 0], 1_000_000).unwrap();
+        assert_eq!(execution.output, vec![3, DIVISION_ERROR, 8]);
+    }
+}
@@ -2,9 +2,16 @@ mod structure;
 mod variables;
 mod procedures;
 mod preprocessor;
+mod lowering;
+mod constants;
+mod globals;
 mod intermediate;
+mod optimizer;
+#[cfg(test)]
+mod vm;
 
 use intermediate::program_translator;
+use optimizer::pipeline::Options;
 use lalrpop_util::lalrpop_mod;
 use std::fs;
 use std::str::FromStr;
@@ -17,15 +24,32 @@ lalrpop_mod!(
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
+    let verbose = args.iter().any(|arg| arg == "-v" || arg == "--verbose");
+    let options = match Options::from_args(&args) {
+        Ok(options) => options,
+        Err(error) => {
+            println!("{}", error);
+            return;
+        }
+    };
+    let print_passes = args.iter().any(|arg| arg == "--print-passes");
+    let args: Vec<&String> = args.iter().filter(|arg| !arg.starts_with('-')).collect();
     let parser = grammar::program_allParser::new();
     // let file = fs::read_to_string("program3.imp").unwrap();
-    let file = fs::read_to_string(&args[1]).unwrap();
+    let file = fs::read_to_string(args[1]).unwrap();
+    if print_passes {
+        if let Err(error) = optimizer::pipeline::print_passes(&file, &options) {
+            println!("{}", error);
+        }
+    }
     // let file = fs::read_to_string("testy/example9.imp").unwrap();
     // let file = fs::read_to_string("myprogram.imp").unwrap();
     let ret = parser.parse(&file).unwrap();
     let mut translator = program_translator::Translator::new();
+    translator.verbose = verbose;
+    translator.options = options;
     if let Some(program) = translator.compile(ret) {
-        fs::write(&args[2], program).unwrap();
+        fs::write(args[2], program).unwrap();
     } else {
         println!("Didnt write to file");
     }
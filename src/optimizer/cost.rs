@@ -0,0 +1,126 @@
+use crate::intermediate::Instruction;
+use crate::structure::{AsmLine, Command, Condition, Identifier, Operation, Operator, Value};
+use crate::variables::Pointer;
+
+/// Instructions needed to call built-in multiplication or division, without preparing arguments.
+const ARITHMETIC_CALL_SIZE: usize = 4;
+/// Instructions needed to pass control to a procedure and get it back.
+pub const CALL_SIZE: usize = 4;
+/// Instructions needed to pass one argument to a procedure.
+pub const ARGUMENT_SIZE: usize = 2;
+
+/// Estimates number of instructions generated for the commands.
+pub fn estimate_size(commands: &[Command]) -> usize {
+    commands.iter().map(command_size).sum()
+}
+
+fn command_size(command: &Command) -> usize {
+    match command {
+        Command::Assign(identifier, operation) => {
+            store_size(identifier) + operation_size(operation)
+        }
+        Command::If(condition, commands) => condition_size(condition) + estimate_size(commands),
+        Command::IfElse(condition, commands1, commands2) => {
+            condition_size(condition) + estimate_size(commands1) + estimate_size(commands2) + 1
+        }
+        // Every range is checked with a subtraction and a jump, every branch jumps to the end
+        Command::Case(value, branches, otherwise) => {
+            let branches: usize = branches
+                .iter()
+                .map(|branch| 2 * branch.ranges.len() + estimate_size(&branch.commands) + 1)
+                .sum();
+            value_size(value) + branches + estimate_size(otherwise)
+        }
+        Command::While(condition, commands) => {
+            condition_size(condition) + estimate_size(commands) + 1
+        }
+        Command::Repeat(condition, commands) => condition_size(condition) + estimate_size(commands),
+        Command::For(_, start, end, step, commands) | Command::ForDown(_, start, end, step, commands) => {
+            let step = match step {
+                Value::Literal(_) => 0,
+                step => value_size(step) + 1,
+            };
+            value_size(start) + value_size(end) + step + 2 + estimate_size(commands) + 6
+        }
+        Command::FunctionCall(_, arguments) => arguments.len() * ARGUMENT_SIZE + CALL_SIZE,
+        Command::Read(identifier) => store_size(identifier),
+        Command::Write(value) => value_size(value),
+        Command::Asm(lines) => lines
+            .iter()
+            .filter(|line| matches!(line, AsmLine::Instruction(_, _)))
+            .count(),
+        Command::Break | Command::Continue => 1,
+        Command::Line(_) => 0,
+    }
+}
+
+fn value_size(value: &Value) -> usize {
+    match value {
+        Value::Identifier(Identifier::ArrayVar(_, _)) => 3,
+        // Computed result is stored in a scratch cell
+        Value::Expression(operation) => operation_size(operation) + 1,
+        _ => 1,
+    }
+}
+
+fn store_size(identifier: &Identifier) -> usize {
+    match identifier {
+        Identifier::ArrayVar(_, _) => 4,
+        _ => 1,
+    }
+}
+
+fn operation_size(operation: &Operation) -> usize {
+    let left = value_size(&operation.left);
+    let right = value_size(&operation.right);
+    match operation.operator {
+        Operator::Value => left,
+        Operator::Add | Operator::Subtract => left + right,
+        Operator::Multiply | Operator::Divide | Operator::Modulo => {
+            left + right + 2 + ARITHMETIC_CALL_SIZE
+        }
+        Operator::ShiftLeft | Operator::ShiftRight => match operation.right {
+            Value::Literal(shift) => left + shift.max(0) as usize,
+            _ => left,
+        },
+    }
+}
+
+fn condition_size(condition: &Condition) -> usize {
+    condition
+        .comparisons()
+        .iter()
+        .map(|comparison| {
+            estimate_size(&comparison.before) + value_size(&comparison.left) + value_size(&comparison.right) + 2
+        })
+        .sum()
+}
+
+/// Cost of executing the instruction once on the virtual machine.
+pub fn instruction_cost(instruction: &Instruction) -> usize {
+    match instruction {
+        Instruction::Get(_) | Instruction::Put(_) => 100,
+        Instruction::Load(Pointer::IndirectCell(_))
+        | Instruction::Store(Pointer::IndirectCell(_))
+        | Instruction::Add(Pointer::IndirectCell(_)) => 20,
+        Instruction::Subtr(Pointer::IndirectCell(_)) => 12,
+        Instruction::Load(_)
+        | Instruction::Store(_)
+        | Instruction::Add(_)
+        | Instruction::Subtr(_)
+        | Instruction::Return(_)
+        | Instruction::LoadKPlus3
+        | Instruction::LoadLabel(_) => 10,
+        Instruction::Set(_) => 50,
+        Instruction::Half => 5,
+        Instruction::Jump(_)
+        | Instruction::Jpos(_)
+        | Instruction::Jzero(_)
+        | Instruction::Jneg(_)
+        | Instruction::Goto(_)
+        | Instruction::GoPos(_)
+        | Instruction::GoNeg(_)
+        | Instruction::GoZero(_) => 1,
+        Instruction::Halt => 0,
+    }
+}
@@ -0,0 +1,624 @@
+use crate::structure::{
+    controls_loop, ArgumentDecl, AsmLine, Command, Condition, ConditionOperator, Identifier,
+    Program, Value,
+};
+use std::collections::HashSet;
+use std::mem;
+
+/// Removes assignments whose value is never read, blocks under conditions with known result
+/// and procedures that can not be called from the main program.
+///
+/// Returns descriptions of removed code.
+pub fn eliminate_dead_code(program: &mut Program) -> Vec<String> {
+    let mut report = vec![];
+    for procedure in program.procedures.iter_mut() {
+        let arguments = procedure
+            .arguments
+            .iter()
+            .map(|argument| match argument {
+                ArgumentDecl::VariableArg(name)
+                | ArgumentDecl::ArrayArg(name)
+                | ArgumentDecl::MatrixArg(name, _) => name.clone(),
+            })
+            .collect();
+        let scope = Scope::new(
+            format!("procedure {}", procedure.name),
+            &procedure.commands,
+            arguments,
+        );
+        // Local variables keep their values between calls
+        let live = scope.read.union(&scope.protected).cloned().collect();
+        scope.remove_dead(&mut procedure.commands, 0, live, &mut report);
+    }
+
+    let scope = Scope::new("main".to_string(), &program.commands, HashSet::new());
+    scope.remove_dead(&mut program.commands, 0, HashSet::new(), &mut report);
+
+    remove_unused_procedures(program, &mut report);
+    report
+}
+
+/// Variables whose values from before the commands can be read by them.
+pub fn live_at_entry(commands: &[Command]) -> HashSet<String> {
+    let scope = Scope::new(String::new(), commands, HashSet::new());
+    scope.live_in(commands, 0, HashSet::new())
+}
+
+/// Information about a procedure or main program needed to decide which commands can be removed.
+///
+/// Commands are numbered in the order they are translated, which is also the order
+/// in which the compiler checks that variables are initialized before being read.
+struct Scope {
+    name: String,
+    /// Arguments passed by reference, writing to them is visible to the caller.
+    protected: HashSet<String>,
+    /// Variables read anywhere in the scope.
+    read: HashSet<String>,
+    /// Positions of commands initializing variables, which are read somewhere.
+    first_writes: Vec<usize>,
+}
+
+impl Scope {
+    fn new(name: String, commands: &[Command], protected: HashSet<String>) -> Self {
+        let mut writes = vec![];
+        let mut read = HashSet::new();
+        collect_accesses(commands, &mut 0, &mut writes, &mut read);
+
+        let mut written = HashSet::new();
+        let first_writes = writes
+            .into_iter()
+            .filter(|(name, _)| written.insert(name.clone()) && read.contains(name))
+            .map(|(_, position)| position)
+            .collect();
+
+        Scope {
+            name,
+            protected,
+            read,
+            first_writes,
+        }
+    }
+
+    /// Whether removing commands numbered `start..start + count` can leave a variable uninitialized.
+    fn initializes(&self, start: usize, count: usize) -> bool {
+        self.first_writes
+            .iter()
+            .any(|position| (start..start + count).contains(position))
+    }
+
+    /// Removes dead code from commands starting at `position`, returns variables live before them.
+    fn remove_dead(
+        &self,
+        commands: &mut Vec<Command>,
+        position: usize,
+        live: HashSet<String>,
+        report: &mut Vec<String>,
+    ) -> HashSet<String> {
+        let mut result = vec![];
+        let live = self.sequence(mem::take(commands), position, live, &mut result, report);
+        result.reverse();
+        *commands = result;
+        live
+    }
+
+    /// Computes variables live before commands without changing them.
+    fn live_in(
+        &self,
+        commands: &[Command],
+        position: usize,
+        live: HashSet<String>,
+    ) -> HashSet<String> {
+        self.sequence(commands.to_vec(), position, live, &mut vec![], &mut vec![])
+    }
+
+    /// Goes through commands backwards, pushing the ones kept in reverse order.
+    fn sequence(
+        &self,
+        commands: Vec<Command>,
+        position: usize,
+        mut live: HashSet<String>,
+        result: &mut Vec<Command>,
+        report: &mut Vec<String>,
+    ) -> HashSet<String> {
+        let mut positions = vec![];
+        let mut next = position;
+        for command in &commands {
+            positions.push(next);
+            next += count_commands(command);
+        }
+        for (command, position) in commands.into_iter().zip(positions).rev() {
+            live = self.command(command, position, live, result, report);
+        }
+        live
+    }
+
+    fn command(
+        &self,
+        command: Command,
+        position: usize,
+        mut live: HashSet<String>,
+        result: &mut Vec<Command>,
+        report: &mut Vec<String>,
+    ) -> HashSet<String> {
+        let count = count_commands(&command);
+        match command {
+            Command::Assign(Identifier::Variable(name), operation)
+                if !live.contains(&name)
+                    && !self.protected.contains(&name)
+                    && !self.first_writes.contains(&position) =>
+            {
+                report.push(format!(
+                    "Removed dead store {} := {} in {}",
+                    Value::Identifier(Identifier::Variable(name)),
+                    operation,
+                    self.name
+                ));
+                return live;
+            }
+            Command::Assign(identifier, operation) => {
+                kill_identifier(&identifier, &mut live);
+                use_value(&operation.left, &mut live);
+                use_value(&operation.right, &mut live);
+                result.push(Command::Assign(identifier, operation));
+            }
+            Command::Read(identifier) => {
+                kill_identifier(&identifier, &mut live);
+                result.push(Command::Read(identifier));
+            }
+            Command::Write(value) => {
+                use_value(&value, &mut live);
+                result.push(Command::Write(value));
+            }
+            Command::FunctionCall(name, arguments) => {
+                // Procedure can read any of the arguments
+                live.extend(arguments.iter().cloned());
+                result.push(Command::FunctionCall(name, arguments));
+            }
+            Command::Break | Command::Continue => {
+                // Jump can go anywhere in the loop or after it, everything read in the scope is kept
+                live = self.read.union(&self.protected).cloned().collect();
+                result.push(command);
+            }
+            Command::Line(_) => result.push(command),
+            Command::Asm(lines) => {
+                // Jumps inside the block make the order of accesses unknown
+                for identifier in lines.iter().filter_map(AsmLine::identifier) {
+                    use_value(&Value::Identifier(identifier.clone()), &mut live);
+                }
+                result.push(Command::Asm(lines));
+            }
+            Command::If(condition, mut commands) => match evaluate(&condition) {
+                Some(false) if !self.initializes(position, count) => {
+                    report.push(format!(
+                        "Removed unreachable IF {} in {}",
+                        condition, self.name
+                    ));
+                    return live;
+                }
+                Some(true) => {
+                    report.push(format!(
+                        "Removed condition of IF {} in {}",
+                        condition, self.name
+                    ));
+                    return self.sequence(commands, position + 1, live, result, report);
+                }
+                _ => {
+                    let body = self.remove_dead(&mut commands, position + 1, live.clone(), report);
+                    live.extend(body);
+                    use_condition(&condition, &mut live);
+                    result.push(Command::If(condition, commands));
+                }
+            },
+            Command::IfElse(condition, mut yes, mut no) => {
+                let yes_start = position + 1;
+                let no_start = yes_start + yes.iter().map(count_commands).sum::<usize>();
+                match evaluate(&condition) {
+                    Some(true) if !self.initializes(no_start, position + count - no_start) => {
+                        report.push(format!(
+                            "Removed unreachable ELSE of IF {} in {}",
+                            condition, self.name
+                        ));
+                        return self.sequence(yes, yes_start, live, result, report);
+                    }
+                    Some(false) if !self.initializes(yes_start, no_start - yes_start) => {
+                        report.push(format!(
+                            "Removed unreachable THEN of IF {} in {}",
+                            condition, self.name
+                        ));
+                        return self.sequence(no, no_start, live, result, report);
+                    }
+                    _ => {
+                        let yes_live = self.remove_dead(&mut yes, yes_start, live.clone(), report);
+                        let no_live = self.remove_dead(&mut no, no_start, live, report);
+                        live = yes_live.union(&no_live).cloned().collect();
+                        use_condition(&condition, &mut live);
+                        result.push(Command::IfElse(condition, yes, no));
+                    }
+                }
+            }
+            Command::Case(value, mut branches, mut otherwise) => {
+                let mut start = position + 1;
+                let mut starts = vec![];
+                for branch in &branches {
+                    starts.push(start);
+                    start += branch.commands.iter().map(count_commands).sum::<usize>();
+                }
+                let mut entry = HashSet::new();
+                for (branch, start) in branches.iter_mut().zip(starts) {
+                    entry.extend(self.remove_dead(&mut branch.commands, start, live.clone(), report));
+                }
+                entry.extend(self.remove_dead(&mut otherwise, start, live, report));
+                live = entry;
+                use_value(&value, &mut live);
+                result.push(Command::Case(value, branches, otherwise));
+            }
+            Command::While(condition, mut commands) => {
+                if evaluate(&condition) == Some(false) && !self.initializes(position, count) {
+                    report.push(format!(
+                        "Removed unreachable WHILE {} in {}",
+                        condition, self.name
+                    ));
+                    return live;
+                }
+                let mut entry = live.clone();
+                use_condition(&condition, &mut entry);
+                let entry = self.fixpoint(&commands, position + 1, entry);
+                self.remove_dead(&mut commands, position + 1, entry.clone(), report);
+                live = entry;
+                result.push(Command::While(condition, commands));
+            }
+            Command::Repeat(condition, mut commands) => {
+                if evaluate(&condition) == Some(true) && !controls_loop(&commands) {
+                    report.push(format!(
+                        "Removed loop of REPEAT UNTIL {} in {}",
+                        condition, self.name
+                    ));
+                    return self.sequence(commands, position + 1, live, result, report);
+                }
+                let mut exit = live.clone();
+                use_condition(&condition, &mut exit);
+                let exit = self.fixpoint(&commands, position + 1, exit);
+                live = self.remove_dead(&mut commands, position + 1, exit, report);
+                result.push(Command::Repeat(condition, commands));
+            }
+            Command::For(iterator, start, end, step, mut commands) => {
+                let exit = self.loop_exit(&iterator, &commands, position, &live);
+                live.extend(self.remove_dead(&mut commands, position + 1, exit, report));
+                kill_iterator(&iterator, &mut live);
+                use_value(&start, &mut live);
+                use_value(&end, &mut live);
+                use_value(&step, &mut live);
+                result.push(Command::For(iterator, start, end, step, commands));
+            }
+            Command::ForDown(iterator, start, end, step, mut commands) => {
+                let exit = self.loop_exit(&iterator, &commands, position, &live);
+                live.extend(self.remove_dead(&mut commands, position + 1, exit, report));
+                kill_iterator(&iterator, &mut live);
+                use_value(&start, &mut live);
+                use_value(&end, &mut live);
+                use_value(&step, &mut live);
+                result.push(Command::ForDown(iterator, start, end, step, commands));
+            }
+        }
+        live
+    }
+
+    /// Variables live after the loop body, which can be followed by the exit or the body itself.
+    fn fixpoint(
+        &self,
+        commands: &[Command],
+        position: usize,
+        exit: HashSet<String>,
+    ) -> HashSet<String> {
+        let mut live = exit.clone();
+        loop {
+            let mut next = self.live_in(commands, position, live.clone());
+            next.extend(exit.iter().cloned());
+            if next == live {
+                return live;
+            }
+            live = next;
+        }
+    }
+
+    fn loop_exit(
+        &self,
+        iterator: &str,
+        commands: &[Command],
+        position: usize,
+        live: &HashSet<String>,
+    ) -> HashSet<String> {
+        let mut exit = live.clone();
+        exit.insert(iterator.to_string());
+        exit.insert(format!("{}_end", iterator));
+        exit.insert(format!("{}_step", iterator));
+        self.fixpoint(commands, position + 1, exit)
+    }
+}
+
+/// Removes procedures which are not called from the main program, directly or through other procedures.
+fn remove_unused_procedures(program: &mut Program, report: &mut Vec<String>) {
+    let mut used = HashSet::new();
+    collect_calls(&program.commands, &mut used);
+    // Procedures can only call ones declared before them
+    for procedure in program.procedures.iter().rev() {
+        if used.contains(&procedure.name) {
+            collect_calls(&procedure.commands, &mut used);
+        }
+    }
+
+    let mut known = HashSet::new();
+    program.procedures.retain(|procedure| {
+        let mut calls = HashSet::new();
+        collect_calls(&procedure.commands, &mut calls);
+        // Calls of unknown procedures have to be reported by the preprocessor
+        let removable = !used.contains(&procedure.name) && calls.is_subset(&known);
+        known.insert(procedure.name.clone());
+        if removable {
+            report.push(format!("Removed unused procedure {}", procedure.name));
+        }
+        !removable
+    });
+}
+
+fn collect_calls(commands: &[Command], calls: &mut HashSet<String>) {
+    for command in commands {
+        match command {
+            Command::FunctionCall(name, _) => {
+                calls.insert(name.clone());
+            }
+            Command::If(condition, commands)
+            | Command::While(condition, commands)
+            | Command::Repeat(condition, commands) => {
+                collect_condition_calls(condition, calls);
+                collect_calls(commands, calls);
+            }
+            Command::For(_, _, _, _, commands) | Command::ForDown(_, _, _, _, commands) => {
+                collect_calls(commands, calls)
+            }
+            Command::IfElse(condition, commands1, commands2) => {
+                collect_condition_calls(condition, calls);
+                collect_calls(commands1, calls);
+                collect_calls(commands2, calls);
+            }
+            Command::Case(_, branches, otherwise) => {
+                for branch in branches {
+                    collect_calls(&branch.commands, calls);
+                }
+                collect_calls(otherwise, calls);
+            }
+            Command::Assign(_, _)
+            | Command::Read(_)
+            | Command::Write(_)
+            | Command::Asm(_)
+            | Command::Break
+            | Command::Continue
+            | Command::Line(_) => {}
+        }
+    }
+}
+
+fn collect_condition_calls(condition: &Condition, calls: &mut HashSet<String>) {
+    for comparison in condition.comparisons() {
+        collect_calls(&comparison.before, calls);
+    }
+}
+
+/// Number of commands including nested ones, used to give every command its position.
+fn count_commands(command: &Command) -> usize {
+    let count = |commands: &[Command]| commands.iter().map(count_commands).sum::<usize>();
+    1 + match command {
+        Command::If(_, commands)
+        | Command::While(_, commands)
+        | Command::Repeat(_, commands)
+        | Command::For(_, _, _, _, commands)
+        | Command::ForDown(_, _, _, _, commands) => count(commands),
+        Command::IfElse(_, commands1, commands2) => count(commands1) + count(commands2),
+        Command::Case(_, branches, otherwise) => {
+            branches.iter().map(|branch| count(&branch.commands)).sum::<usize>() + count(otherwise)
+        }
+        Command::Assign(_, _)
+        | Command::FunctionCall(_, _)
+        | Command::Read(_)
+        | Command::Write(_)
+        | Command::Asm(_)
+        | Command::Break
+        | Command::Continue
+        | Command::Line(_) => 0,
+    }
+}
+
+/// Collects positions of variable writes and names of everything read, in translation order.
+fn collect_accesses(
+    commands: &[Command],
+    position: &mut usize,
+    writes: &mut Vec<(String, usize)>,
+    read: &mut HashSet<String>,
+) {
+    for command in commands {
+        let current = *position;
+        *position += 1;
+        match command {
+            Command::Assign(identifier, operation) => {
+                use_value(&operation.left, read);
+                use_value(&operation.right, read);
+                write_identifier(identifier, current, writes, read);
+            }
+            Command::Read(identifier) => write_identifier(identifier, current, writes, read),
+            Command::Write(value) => use_value(value, read),
+            Command::FunctionCall(_, arguments) => read.extend(arguments.iter().cloned()),
+            Command::Break | Command::Continue | Command::Line(_) => {}
+            Command::Asm(lines) => {
+                for line in lines {
+                    match line.identifier() {
+                        Some(identifier) if line.writes() => {
+                            write_identifier(identifier, current, writes, read)
+                        }
+                        Some(identifier) => use_value(&Value::Identifier(identifier.clone()), read),
+                        None => {}
+                    }
+                }
+            }
+            Command::If(condition, commands)
+            | Command::While(condition, commands)
+            | Command::Repeat(condition, commands) => {
+                use_condition(condition, read);
+                collect_accesses(commands, position, writes, read);
+            }
+            Command::IfElse(condition, commands1, commands2) => {
+                use_condition(condition, read);
+                collect_accesses(commands1, position, writes, read);
+                collect_accesses(commands2, position, writes, read);
+            }
+            Command::Case(value, branches, otherwise) => {
+                use_value(value, read);
+                for branch in branches {
+                    collect_accesses(&branch.commands, position, writes, read);
+                }
+                collect_accesses(otherwise, position, writes, read);
+            }
+            Command::For(_, start, end, step, commands) | Command::ForDown(_, start, end, step, commands) => {
+                use_value(start, read);
+                use_value(end, read);
+                use_value(step, read);
+                collect_accesses(commands, position, writes, read);
+            }
+        }
+    }
+}
+
+fn write_identifier(
+    identifier: &Identifier,
+    position: usize,
+    writes: &mut Vec<(String, usize)>,
+    read: &mut HashSet<String>,
+) {
+    match identifier {
+        Identifier::Variable(name) => writes.push((name.clone(), position)),
+        Identifier::ArrayLit(_, _) => {}
+        Identifier::ArrayVar(_, index) => {
+            read.insert(index.clone());
+        }
+        Identifier::Element(_, indices) => {
+            indices.iter().for_each(|index| use_value(index, read));
+        }
+    }
+}
+
+/// Iterator, its end and step are set when the loop starts.
+fn kill_iterator(iterator: &str, live: &mut HashSet<String>) {
+    live.remove(iterator);
+    live.remove(&format!("{}_end", iterator));
+    live.remove(&format!("{}_step", iterator));
+}
+
+fn kill_identifier(identifier: &Identifier, live: &mut HashSet<String>) {
+    match identifier {
+        Identifier::Variable(name) => {
+            live.remove(name);
+        }
+        Identifier::ArrayLit(_, _) => {}
+        Identifier::ArrayVar(_, index) => {
+            live.insert(index.clone());
+        }
+        Identifier::Element(_, indices) => {
+            indices.iter().for_each(|index| use_value(index, live));
+        }
+    }
+}
+
+fn use_value(value: &Value, live: &mut HashSet<String>) {
+    match value {
+        Value::Literal(_) => {}
+        Value::Identifier(Identifier::Variable(name))
+        | Value::Identifier(Identifier::ArrayLit(name, _)) => {
+            live.insert(name.clone());
+        }
+        Value::Identifier(Identifier::ArrayVar(name, index)) => {
+            live.insert(name.clone());
+            live.insert(index.clone());
+        }
+        Value::Identifier(Identifier::Element(name, indices)) => {
+            live.insert(name.clone());
+            indices.iter().for_each(|index| use_value(index, live));
+        }
+        Value::Call(_, arguments) => {
+            live.extend(arguments.iter().cloned());
+        }
+        Value::Expression(operation) => {
+            use_value(&operation.left, live);
+            use_value(&operation.right, live);
+        }
+    }
+}
+
+fn use_condition(condition: &Condition, live: &mut HashSet<String>) {
+    for comparison in condition.comparisons() {
+        // Variables written before the comparison are only temporaries read by it
+        collect_accesses(&comparison.before, &mut 0, &mut vec![], live);
+        use_value(&comparison.left, live);
+        use_value(&comparison.right, live);
+    }
+}
+
+/// Result of the condition, if it is known from comparisons of literals.
+pub fn evaluate(condition: &Condition) -> Option<bool> {
+    // Commands run before comparisons can call procedures or trap, so they are not skipped
+    if condition.comparisons().iter().any(|comparison| !comparison.before.is_empty()) {
+        return None;
+    }
+    evaluate_literals(condition)
+}
+
+fn evaluate_literals(condition: &Condition) -> Option<bool> {
+    let comparison = match condition {
+        Condition::Compare(comparison) => comparison,
+        Condition::Not(condition) => return evaluate_literals(condition).map(|result| !result),
+        Condition::And(first, second) => {
+            return match (evaluate_literals(first), evaluate_literals(second)) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            }
+        }
+        Condition::Or(first, second) => {
+            return match (evaluate_literals(first), evaluate_literals(second)) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            }
+        }
+    };
+    let (Value::Literal(left), Value::Literal(right)) = (&comparison.left, &comparison.right) else {
+        return None;
+    };
+    Some(match comparison.operator {
+        ConditionOperator::Equal => left == right,
+        ConditionOperator::NotEqual => left != right,
+        ConditionOperator::Lesser => left < right,
+        ConditionOperator::Greater => left > right,
+        ConditionOperator::LesserEqual => left <= right,
+        ConditionOperator::GreaterEqual => left >= right,
+    })
+}
+
+#[test]
+fn removes_dead_stores_and_unused_procedures() {
+    use crate::grammar::program_allParser;
+
+    let mut program = program_allParser::new()
+        .parse(
+            "PROCEDURE p(a) IS BEGIN a := 1; END
+            PROGRAM IS x, y BEGIN
+                x := 1; y := 2; x := 3; WRITE x;
+                IF 1 > 2 THEN p(x); ENDIF
+            END",
+        )
+        .unwrap();
+    let report = eliminate_dead_code(&mut program);
+
+    // x := 1 initializes x, so only y := 2 is a dead store
+    assert_eq!(program.commands.len(), 3);
+    assert!(program.procedures.is_empty());
+    assert_eq!(report.len(), 3);
+}
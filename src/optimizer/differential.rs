@@ -0,0 +1,130 @@
+use crate::grammar::program_allParser;
+use crate::intermediate::program_translator::Translator;
+use crate::optimizer::pipeline::{Options, OVERFLOW_BOUND};
+use crate::vm::{run, Execution};
+use std::fs;
+use std::path::Path;
+
+/// Optimization levels compared with each other.
+const LEVELS: [&str; 4] = ["0", "1", "2", "s"];
+/// Directories with compared programs, relative to the crate.
+const DIRECTORIES: [&str; 3] = ["testy", "programy", "testy_roznicowe"];
+/// Inputs of programs, programs not listed here read `DEFAULT_INPUT`.
+const INPUTS: [(&str, &[&[i64]]); 15] = [
+    ("example1.imp", &[&[1234, 567], &[12, 0]]),
+    ("example2.imp", &[&[0, 1], &[3, 5]]),
+    ("example3.imp", &[&[1], &[7]]),
+    ("example4.imp", &[&[20, 9], &[10, 3]]),
+    ("example5.imp", &[&[1234567890, 1234567890987654321, 987654321]]),
+    ("example6.imp", &[&[20], &[1]]),
+    ("example7.imp", &[&[0, 0, 0], &[1, 0, 2]]),
+    ("example9.imp", &[&[20, 9]]),
+    ("program2.imp", &[&[340], &[1]]),
+    ("program3.imp", &[&[60, 48, 30, 12], &[-7, 3, 0, 5]]),
+    ("ujemne.imp", &[&[-28, 4], &[-24, -4], &[-13, 5], &[13, -5], &[-12, -5], &[7, 5]]),
+    ("funkcje.imp", &[&[5, 3], &[-4, 7], &[2, 0], &[6, 1]]),
+    ("pulapki.imp", &[&[7, 3], &[7, 0], &[3, 100000]]),
+    ("sterowanie.imp", &[&[7], &[0], &[12]]),
+    ("tablice.imp", &[&[1, -1], &[3, 2], &[2, 3], &[4, 7]]),
+];
+const DEFAULT_INPUT: &[i64] = &[123, 5, 7, 3, 0, 11, 2, 9, 4, 1];
+const MAX_STEPS: usize = 100_000_000;
+
+fn compile(source: &str, options: Options) -> Option<String> {
+    let program = program_allParser::new().parse(source).ok()?;
+    let mut translator = Translator::new();
+    translator.options = options;
+    translator.translate(program).ok()?;
+    Some(translator.to_code(false))
+}
+
+/// Options for level given after `-O`, checking indices and arithmetic at runtime when `checked` is set.
+fn options(level: &str, checked: bool) -> Options {
+    let mut options = Options::level(level).unwrap();
+    if checked {
+        options.bounds_check = true;
+        options.overflow_bound = Some(OVERFLOW_BOUND);
+    }
+    options
+}
+
+fn inputs(name: &str) -> &'static [&'static [i64]] {
+    INPUTS
+        .iter()
+        .find(|(program, _)| *program == name)
+        .map_or(&[DEFAULT_INPUT], |(_, inputs)| inputs)
+}
+
+/// Compiles every program at every optimization level, with and without runtime checks, runs all versions
+/// on the same inputs and checks that they all finish and write the same values. Prints costs of every level.
+#[test]
+fn optimization_levels_agree() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut paths: Vec<_> = DIRECTORIES
+        .iter()
+        .flat_map(|directory| fs::read_dir(root.join(directory)).unwrap())
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "imp"))
+        .collect();
+    paths.sort();
+
+    let header: Vec<String> = LEVELS
+        .iter()
+        .map(|level| format!("{:>12}", format!("-O{}", level)))
+        .collect();
+    println!("{:<16} {:>6} {:>7} {}", "program", "input", "checked", header.join(" "));
+    let mut failures = vec![];
+    for (path, checked) in paths.iter().flat_map(|path| [(path, false), (path, true)]) {
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let source = fs::read_to_string(path).unwrap();
+        let codes: Vec<_> = LEVELS
+            .iter()
+            .map(|level| compile(&source, options(level, checked)))
+            .collect();
+        if codes.iter().all(Option::is_none) {
+            continue;
+        }
+        if codes.iter().any(Option::is_none) {
+            failures.push(format!("{} checked {}: compiles only at some levels", name, checked));
+            continue;
+        }
+
+        for (number, input) in inputs(&name).iter().enumerate() {
+            let results: Vec<Result<Execution, String>> = codes
+                .iter()
+                .map(|code| run(code.as_ref().unwrap(), input, MAX_STEPS))
+                .collect();
+            let costs: Vec<String> = results
+                .iter()
+                .map(|result| match result {
+                    Ok(execution) => format!("{:>12}", execution.cost),
+                    Err(_) => format!("{:>12}", "error"),
+                })
+                .collect();
+            println!("{:<16} {:>6} {:>7} {}", name, number + 1, checked, costs.join(" "));
+
+            let mut outputs = vec![];
+            for (level, result) in LEVELS.iter().zip(&results) {
+                match result {
+                    Ok(execution) => outputs.push(&execution.output),
+                    Err(error) => failures.push(format!(
+                        "{} input {} checked {}: -O{} fails: {}",
+                        name, number + 1, checked, level, error
+                    )),
+                }
+            }
+            if outputs.len() < LEVELS.len() {
+                continue;
+            }
+            for (level, output) in LEVELS.iter().zip(&outputs).skip(1) {
+                if *output != outputs[0] {
+                    failures.push(format!(
+                        "{} input {} checked {}: -O{} gives {:?}, -O0 gives {:?}",
+                        name, number + 1, checked, level, output, outputs[0]
+                    ));
+                }
+            }
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
@@ -0,0 +1,108 @@
+use crate::optimizer::cost::{ARGUMENT_SIZE, CALL_SIZE};
+use crate::procedures::ProcedureHandler;
+
+/// Instructions added at the end of a procedure translated as subroutine.
+const RETURN_SIZE: usize = 2;
+/// Execution cost of passing control to a procedure and back: LOAD, STORE, JUMP and RTRN.
+const CALL_COST: usize = 31;
+/// Execution cost of passing one argument, LOAD of its address and STORE.
+const ARGUMENT_COST: usize = 20;
+/// Estimated number of executions of a call placed inside a loop.
+const LOOP_WEIGHT: usize = 10;
+/// Execution cost that has to be saved for every instruction added by inlining.
+const INSTRUCTION_PRICE: usize = 5;
+/// Maximal number of instructions inlining a single procedure can add.
+const MAX_GROWTH: usize = 400;
+
+/// Decides whether calls of the procedure should be replaced with its body,
+/// comparing code size added by inlining with execution cost of calls saved by it.
+///
+/// `uses` is the number of calls, `loop_uses` the number of those placed inside loops.
+/// When optimizing for size (`small`) procedures are inlined only if that does not make the code larger.
+/// Returns the decision with its reason.
+///
+/// Procedures keeping state are never inlined, even when called once, since the caller can be inlined
+/// in many places, each copy getting its own variables. Callees are decided before their callers,
+/// so procedures inlined into a caller never keep state and copying the caller is safe.
+pub fn decide_inlining(
+    procedure: &dyn ProcedureHandler,
+    uses: usize,
+    loop_uses: usize,
+    small: bool,
+) -> (bool, String) {
+    if procedure.recursive() {
+        return (false, "recursive".to_string());
+    }
+    if procedure.inline_hint() == Some(false) {
+        return (false, "marked NOINLINE".to_string());
+    }
+    if procedure.keeps_state() {
+        return (
+            false,
+            "local variables keep values between calls".to_string(),
+        );
+    }
+    if uses == 1 {
+        return (true, "called once".to_string());
+    }
+    if procedure.inline_hint() == Some(true) {
+        return (true, "marked INLINE".to_string());
+    }
+
+    let size = procedure.size();
+    let arguments = procedure.arguments();
+    let inlined = uses * size;
+    let shared = size + RETURN_SIZE + uses * (CALL_SIZE + arguments * ARGUMENT_SIZE);
+    if inlined <= shared {
+        return (
+            true,
+            format!("{} instructions inlined, {} as subroutine", inlined, shared),
+        );
+    }
+
+    let growth = inlined - shared;
+    if small {
+        return (false, format!("{} more instructions", growth));
+    }
+    let executions = uses - loop_uses + loop_uses * LOOP_WEIGHT;
+    let saving = executions * (CALL_COST + arguments * ARGUMENT_COST);
+    let reason = format!("{} more instructions, saving about {} cost", growth, saving);
+    (
+        growth <= MAX_GROWTH && saving >= growth * INSTRUCTION_PRICE,
+        reason,
+    )
+}
+
+#[test]
+fn keeps_stateful_procedures_shared() {
+    use crate::grammar::program_allParser;
+    use crate::procedures::regular::RegularProcedure;
+
+    let mut program = program_allParser::new()
+        .parse(
+            "PROCEDURE inc(a) IS BEGIN a := a + 1; END
+            PROCEDURE count(a) IS c BEGIN IF a = 0 THEN c := 0; ELSE c := c + 1; ENDIF a := c; END
+            NOINLINE PROCEDURE once(a) IS BEGIN a := 1; END
+            PROGRAM IS x BEGIN READ x; inc(x); inc(x); count(x); count(x); once(x); WRITE x; END",
+        )
+        .unwrap();
+    let once = RegularProcedure::new(program.procedures.pop().unwrap());
+    let count = RegularProcedure::new(program.procedures.pop().unwrap());
+    let inc = RegularProcedure::new(program.procedures.pop().unwrap());
+
+    assert!(decide_inlining(&inc, 2, 0, false).0);
+    assert!(!decide_inlining(&count, 2, 0, false).0);
+    assert!(!decide_inlining(&once, 1, 0, false).0);
+}
+
+#[test]
+fn keeps_state_of_procedures_called_through_wrappers() {
+    use crate::vm::compile_and_run;
+
+    let program = "PROCEDURE q(a) IS c BEGIN IF a = 0 THEN c := 0; ELSE c := c + 1; ENDIF a := c; END
+        PROCEDURE p(a) IS BEGIN q(a); END
+        PROGRAM IS x BEGIN x := 0; p(x); WRITE x; x := 1; p(x); WRITE x; x := 1; p(x); WRITE x; END";
+    for level in ["0", "1", "2", "s"] {
+        assert_eq!(compile_and_run(program, level, &[]), vec![0, 1, 2], "level {}", level);
+    }
+}
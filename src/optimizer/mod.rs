@@ -0,0 +1,9 @@
+//! Transformations of the parsed program done before it is translated.
+
+pub mod cost;
+pub mod dead_code;
+#[cfg(test)]
+mod differential;
+pub mod inline;
+pub mod pipeline;
+pub mod unroll;
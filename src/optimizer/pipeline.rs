@@ -0,0 +1,231 @@
+use crate::grammar::program_allParser;
+use crate::intermediate::program_translator::Translator;
+use crate::optimizer::cost::instruction_cost;
+
+/// Largest bound of checked arithmetic, product of two values within it does not overflow.
+pub const OVERFLOW_BOUND: i64 = 3_037_000_499;
+
+/// Optimizations which can be turned on and off, in the order they are applied.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pass {
+    /// Unrolling FOR loops with literal bounds.
+    Unroll,
+    /// Removing dead stores, unreachable blocks and unused procedures.
+    DeadCode,
+    /// Replacing multiplication and division by powers of two with shifts.
+    Shifts,
+    /// Inlining procedures based on estimated cost.
+    Inline,
+    /// Hoisting loop invariant expressions and addresses.
+    Licm,
+    /// Moving pointers to array elements together with loop iterators.
+    Induction,
+    /// Computing `% 2` without calling division.
+    Mod2,
+    /// Skipping loads of values already present in the accumulator or scratch cells.
+    Loads,
+    /// Reusing results of already computed expressions.
+    Cse,
+}
+
+impl Pass {
+    pub const ALL: [Pass; 9] = [
+        Pass::Unroll,
+        Pass::DeadCode,
+        Pass::Shifts,
+        Pass::Inline,
+        Pass::Licm,
+        Pass::Induction,
+        Pass::Mod2,
+        Pass::Loads,
+        Pass::Cse,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pass::Unroll => "unroll",
+            Pass::DeadCode => "dead-code",
+            Pass::Shifts => "shifts",
+            Pass::Inline => "inline",
+            Pass::Licm => "licm",
+            Pass::Induction => "induction",
+            Pass::Mod2 => "mod2",
+            Pass::Loads => "loads",
+            Pass::Cse => "cse",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Pass> {
+        Pass::ALL.into_iter().find(|pass| pass.name() == name)
+    }
+}
+
+/// Optimizations chosen for compilation.
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    enabled: [bool; Pass::ALL.len()],
+    /// Prefer smaller code over lower execution cost.
+    pub size: bool,
+    /// Check indices of arrays at runtime, halting when one is outside bounds.
+    pub bounds_check: bool,
+    /// Largest absolute value of results of arithmetic checked at runtime together with division by zero,
+    /// nothing is checked when not set.
+    pub overflow_bound: Option<i64>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options::level("2").unwrap()
+    }
+}
+
+impl Options {
+    /// Options for level given after `-O`: `0`, `1`, `2` or `s`.
+    pub fn level(level: &str) -> Option<Options> {
+        let passes: &[Pass] = match level {
+            "0" => &[],
+            "1" => &[Pass::DeadCode, Pass::Shifts, Pass::Inline, Pass::Mod2, Pass::Loads],
+            "2" => &Pass::ALL,
+            "s" => &[
+                Pass::DeadCode,
+                Pass::Shifts,
+                Pass::Inline,
+                Pass::Mod2,
+                Pass::Loads,
+                Pass::Cse,
+            ],
+            _ => return None,
+        };
+        let mut options = Options {
+            enabled: [false; Pass::ALL.len()],
+            size: level == "s",
+            bounds_check: false,
+            overflow_bound: None,
+        };
+        for pass in passes {
+            options.set(*pass, true);
+        }
+        Some(options)
+    }
+
+    /// Reads `-O<level>`, `-f<pass>` and `-fno-<pass>` arguments, later ones take precedence.
+    pub fn from_args(args: &[String]) -> Result<Options, String> {
+        let mut options = Options::default();
+        for arg in args {
+            if let Some(level) = arg.strip_prefix("-O") {
+                options = Options::level(level)
+                    .ok_or_else(|| format!("Unknown optimization level {}", arg))?;
+            } else if let Some(name) = arg.strip_prefix("-fno-") {
+                options.set(pass_by_name(name)?, false);
+            } else if let Some(name) = arg.strip_prefix("-f") {
+                options.set(pass_by_name(name)?, true);
+            }
+        }
+        // Levels reset options, so the flag is looked up after them
+        options.bounds_check = args.iter().any(|arg| arg == "--bounds-check");
+        if let Some(arg) = args.iter().find(|arg| arg.starts_with("--checked-arithmetic")) {
+            let bound = match &arg["--checked-arithmetic".len()..] {
+                "" => Some(OVERFLOW_BOUND),
+                bound => bound.strip_prefix('=').and_then(|bound| bound.parse().ok()),
+            };
+            options.overflow_bound = Some(
+                bound
+                    .filter(|bound| (1..=OVERFLOW_BOUND).contains(bound))
+                    .ok_or_else(|| format!("Invalid bound of arithmetic {}", arg))?,
+            );
+        }
+        Ok(options)
+    }
+
+    pub fn is_enabled(&self, pass: Pass) -> bool {
+        self.enabled[pass as usize]
+    }
+
+    pub fn set(&mut self, pass: Pass, enabled: bool) {
+        self.enabled[pass as usize] = enabled;
+    }
+
+    /// Enabled passes in the order they are applied.
+    pub fn pipeline(&self) -> Vec<Pass> {
+        Pass::ALL
+            .into_iter()
+            .filter(|pass| self.is_enabled(*pass))
+            .collect()
+    }
+}
+
+fn pass_by_name(name: &str) -> Result<Pass, String> {
+    Pass::from_name(name).ok_or_else(|| {
+        let names: Vec<&str> = Pass::ALL.iter().map(Pass::name).collect();
+        format!("Unknown pass {}, available: {}", name, names.join(", "))
+    })
+}
+
+/// Compiles the program with growing prefixes of the pipeline and prints
+/// number of instructions and their summed cost after every pass.
+pub fn print_passes(source: &str, options: &Options) -> Result<(), String> {
+    let pipeline = options.pipeline();
+    println!(
+        "Pipeline: {}",
+        pipeline.iter().map(Pass::name).collect::<Vec<_>>().join(" -> ")
+    );
+    println!("Static cost is the sum of instruction costs, every instruction counted once");
+
+    let mut previous: Option<(usize, usize)> = None;
+    for count in 0..=pipeline.len() {
+        let mut prefix = Options::level("0").unwrap();
+        prefix.size = options.size;
+        for pass in &pipeline[..count] {
+            prefix.set(*pass, true);
+        }
+        let (instructions, cost) = measure(source, prefix)?;
+
+        let name = match count {
+            0 => "(none)",
+            _ => pipeline[count - 1].name(),
+        };
+        match previous {
+            None => println!("{:<12} instructions {:>6}, static cost {:>8}", name, instructions, cost),
+            Some((last_instructions, last_cost)) => println!(
+                "{:<12} instructions {:>6} ({:+}), static cost {:>8} ({:+})",
+                name,
+                instructions,
+                instructions as i64 - last_instructions as i64,
+                cost,
+                cost as i64 - last_cost as i64
+            ),
+        }
+        previous = Some((instructions, cost));
+    }
+    Ok(())
+}
+
+fn measure(source: &str, options: Options) -> Result<(usize, usize), String> {
+    let program = program_allParser::new()
+        .parse(source)
+        .map_err(|error| format!("{:?}", error))?;
+    let mut translator = Translator::new();
+    translator.options = options;
+    translator
+        .translate(program)
+        .map_err(|error| format!("{:?}", error))?;
+    let instructions = &translator.program.instructions;
+    Ok((
+        instructions.len(),
+        instructions
+            .iter()
+            .map(|line| instruction_cost(&line.instruction))
+            .sum(),
+    ))
+}
+
+#[test]
+fn parses_levels_and_toggles() {
+    let args = ["-O1", "-fno-inline", "-fcse"].map(String::from);
+    let options = Options::from_args(&args).unwrap();
+    assert!(options.is_enabled(Pass::Shifts));
+    assert!(!options.is_enabled(Pass::Inline));
+    assert!(options.is_enabled(Pass::Cse));
+    assert!(!options.is_enabled(Pass::Unroll));
+    assert!(Options::from_args(&["-fnothing".to_string()]).is_err());
+}
@@ -0,0 +1,305 @@
+use crate::optimizer::cost::estimate_size;
+use crate::structure::{
+    controls_loop, AsmLine, CaseBranch, Command, Condition, Identifier, Operation, Operator, Program, Value,
+};
+
+/// Maximal estimated number of instructions a fully unrolled loop can take.
+pub const MAX_UNROLLED_SIZE: usize = 100;
+/// Maximal number of body copies placed in one iteration of partially unrolled loop.
+pub const MAX_PARTIAL_FACTOR: i64 = 4;
+
+/// Replaces FOR loops with literal bounds and small bodies with copies of the body,
+/// in which the iterator is replaced with consecutive literals.
+pub fn unroll_program(program: &mut Program) {
+    for procedure in program.procedures.iter_mut() {
+        unroll_commands(&mut procedure.commands);
+    }
+    unroll_commands(&mut program.commands);
+}
+
+fn unroll_commands(commands: &mut Vec<Command>) {
+    let mut result = Vec::with_capacity(commands.len());
+    for mut command in commands.drain(..) {
+        match &mut command {
+            Command::If(_, commands)
+            | Command::While(_, commands)
+            | Command::Repeat(_, commands) => unroll_commands(commands),
+            Command::IfElse(_, commands1, commands2) => {
+                unroll_commands(commands1);
+                unroll_commands(commands2);
+            }
+            Command::Case(_, branches, otherwise) => {
+                for branch in branches {
+                    unroll_commands(&mut branch.commands);
+                }
+                unroll_commands(otherwise);
+            }
+            Command::For(_, _, _, _, commands) | Command::ForDown(_, _, _, _, commands) => {
+                unroll_commands(commands)
+            }
+            Command::Assign(_, _)
+            | Command::FunctionCall(_, _)
+            | Command::Read(_)
+            | Command::Write(_)
+            | Command::Asm(_)
+            | Command::Break
+            | Command::Continue
+            | Command::Line(_) => {}
+        }
+
+        match full_unroll(&command) {
+            Some(copies) => result.extend(copies),
+            None => result.push(command),
+        }
+    }
+    *commands = result;
+}
+
+/// Number of iterations of the loop going from `first` towards `last` by `step`,
+/// if it is known and the loop executes at least once.
+pub fn trip_count(first: &Value, last: &Value, step: &Value) -> Option<i64> {
+    let (Value::Literal(first), Value::Literal(last), Value::Literal(step)) = (first, last, step) else {
+        return None;
+    };
+    let distance = last.checked_sub(*first)?;
+    if distance < 0 || *step <= 0 {
+        return None;
+    }
+    (distance / step).checked_add(1)
+}
+
+/// Number of body copies placed in one iteration of the loop,
+/// the largest divisor of the trip count keeping the loop within size limit.
+pub fn partial_factor(count: Option<i64>, commands: &[Command]) -> i64 {
+    let Some(count) = count else {
+        return 1;
+    };
+    if controls_loop(commands) {
+        return 1;
+    }
+    let size = estimate_size(commands) as i64;
+    (1..=MAX_PARTIAL_FACTOR)
+        .rev()
+        .find(|factor| count % factor == 0 && factor * size <= MAX_UNROLLED_SIZE as i64)
+        .unwrap_or(1)
+}
+
+fn full_unroll(command: &Command) -> Option<Vec<Command>> {
+    let (iterator, start, step, count, commands) = match command {
+        Command::For(iterator, Value::Literal(start), end, step @ Value::Literal(amount), commands) => (
+            iterator,
+            *start,
+            *amount,
+            trip_count(&Value::Literal(*start), end, step)?,
+            commands,
+        ),
+        Command::ForDown(iterator, Value::Literal(start), end, step @ Value::Literal(amount), commands) => (
+            iterator,
+            *start,
+            -*amount,
+            trip_count(end, &Value::Literal(*start), step)?,
+            commands,
+        ),
+        _ => return None,
+    };
+    let size = (count as usize).checked_mul(estimate_size(commands))?;
+    if size > MAX_UNROLLED_SIZE || !can_substitute(iterator, commands) || controls_loop(commands) {
+        return None;
+    }
+
+    let mut result = vec![];
+    for k in 0..count {
+        let value = start + k * step;
+        for command in commands {
+            result.push(substitute_command(command, iterator, value));
+        }
+    }
+    // Substituted bounds of inner loops can make them unrollable
+    unroll_commands(&mut result);
+    Some(result)
+}
+
+/// Whether every use of the iterator in commands can be replaced with a literal.
+fn can_substitute(iterator: &str, commands: &[Command]) -> bool {
+    commands.iter().all(|command| match command {
+        Command::Assign(Identifier::Variable(name), _)
+        | Command::Read(Identifier::Variable(name)) => name != iterator,
+        Command::Assign(_, _)
+        | Command::Read(_)
+        | Command::Write(_)
+        | Command::Break
+        | Command::Continue
+        | Command::Line(_) => true,
+        Command::If(condition, commands) | Command::While(condition, commands) | Command::Repeat(condition, commands) => {
+            can_substitute_condition(iterator, condition) && can_substitute(iterator, commands)
+        }
+        Command::IfElse(condition, commands1, commands2) => {
+            can_substitute_condition(iterator, condition)
+                && can_substitute(iterator, commands1)
+                && can_substitute(iterator, commands2)
+        }
+        Command::Case(_, branches, otherwise) => {
+            branches
+                .iter()
+                .all(|branch| can_substitute(iterator, &branch.commands))
+                && can_substitute(iterator, otherwise)
+        }
+        Command::For(inner, _, _, _, commands) | Command::ForDown(inner, _, _, _, commands) => {
+            inner != iterator && can_substitute(iterator, commands)
+        }
+        // Procedures take arguments by reference, literal can not be passed
+        Command::FunctionCall(_, arguments) => !arguments.iter().any(|name| name == iterator),
+        Command::Asm(lines) => !lines.iter().filter_map(AsmLine::identifier).any(|identifier| {
+            matches!(identifier, Identifier::Variable(name) | Identifier::ArrayVar(_, name) if name == iterator)
+        }),
+    })
+}
+
+fn can_substitute_condition(iterator: &str, condition: &Condition) -> bool {
+    condition
+        .comparisons()
+        .iter()
+        .all(|comparison| can_substitute(iterator, &comparison.before))
+}
+
+fn substitute_commands(commands: &[Command], iterator: &str, value: i64) -> Vec<Command> {
+    commands
+        .iter()
+        .map(|command| substitute_command(command, iterator, value))
+        .collect()
+}
+
+fn substitute_command(command: &Command, iterator: &str, value: i64) -> Command {
+    let commands = |commands: &[Command]| substitute_commands(commands, iterator, value);
+    let condition = |condition: &Condition| {
+        let mut condition = condition.clone();
+        for comparison in condition.comparisons_mut() {
+            comparison.left = substitute_value(&comparison.left, iterator, value);
+            comparison.right = substitute_value(&comparison.right, iterator, value);
+            comparison.before = substitute_commands(&comparison.before, iterator, value);
+        }
+        condition
+    };
+    match command {
+        Command::Assign(identifier, operation) => Command::Assign(
+            substitute_identifier(identifier, iterator, value),
+            fold_operation(Operation {
+                operator: operation.operator,
+                left: substitute_value(&operation.left, iterator, value),
+                right: substitute_value(&operation.right, iterator, value),
+            }),
+        ),
+        Command::If(cond, body) => Command::If(condition(cond), commands(body)),
+        Command::IfElse(cond, body1, body2) => {
+            Command::IfElse(condition(cond), commands(body1), commands(body2))
+        }
+        Command::Case(checked, branches, otherwise) => Command::Case(
+            substitute_value(checked, iterator, value),
+            branches
+                .iter()
+                .map(|branch| CaseBranch {
+                    ranges: branch.ranges.clone(),
+                    commands: commands(&branch.commands),
+                })
+                .collect(),
+            commands(otherwise),
+        ),
+        Command::While(cond, body) => Command::While(condition(cond), commands(body)),
+        Command::Repeat(cond, body) => Command::Repeat(condition(cond), commands(body)),
+        Command::For(inner, start, end, step, body) => Command::For(
+            inner.clone(),
+            substitute_value(start, iterator, value),
+            substitute_value(end, iterator, value),
+            substitute_value(step, iterator, value),
+            commands(body),
+        ),
+        Command::ForDown(inner, start, end, step, body) => Command::ForDown(
+            inner.clone(),
+            substitute_value(start, iterator, value),
+            substitute_value(end, iterator, value),
+            substitute_value(step, iterator, value),
+            commands(body),
+        ),
+        Command::FunctionCall(name, arguments) => {
+            Command::FunctionCall(name.clone(), arguments.clone())
+        }
+        Command::Read(identifier) => {
+            Command::Read(substitute_identifier(identifier, iterator, value))
+        }
+        Command::Write(written) => Command::Write(substitute_value(written, iterator, value)),
+        Command::Asm(lines) => Command::Asm(lines.clone()),
+        Command::Break => Command::Break,
+        Command::Continue => Command::Continue,
+        Command::Line(line) => Command::Line(*line),
+    }
+}
+
+fn substitute_identifier(identifier: &Identifier, iterator: &str, value: i64) -> Identifier {
+    match identifier {
+        Identifier::ArrayVar(array, index) if index == iterator => {
+            Identifier::ArrayLit(array.clone(), value)
+        }
+        other => other.clone(),
+    }
+}
+
+fn substitute_value(value: &Value, iterator: &str, literal: i64) -> Value {
+    match value {
+        Value::Identifier(Identifier::Variable(name)) if name == iterator => {
+            Value::Literal(literal)
+        }
+        Value::Identifier(identifier) => {
+            Value::Identifier(substitute_identifier(identifier, iterator, literal))
+        }
+        Value::Expression(operation) => match fold_operation(Operation {
+            operator: operation.operator,
+            left: substitute_value(&operation.left, iterator, literal),
+            right: substitute_value(&operation.right, iterator, literal),
+        }) {
+            Operation { operator: Operator::Value, left, .. } => left,
+            operation => Value::Expression(Box::new(operation)),
+        },
+        Value::Literal(_) | Value::Call(_, _) => value.clone(),
+    }
+}
+
+/// Computes operations on two literals, when the result is the same as computed by the program.
+fn fold_operation(operation: Operation) -> Operation {
+    let (Value::Literal(left), Value::Literal(right)) = (&operation.left, &operation.right) else {
+        return operation;
+    };
+    let (left, right) = (*left, *right);
+    let folded = match operation.operator {
+        Operator::Add => left.checked_add(right),
+        Operator::Subtract => left.checked_sub(right),
+        Operator::Multiply => left.checked_mul(right),
+        Operator::Divide if left >= 0 && right > 0 => Some(left / right),
+        Operator::Modulo if left >= 0 && right > 0 => Some(left % right),
+        _ => None,
+    };
+    match folded {
+        Some(result) => Operation {
+            operator: Operator::Value,
+            left: Value::Literal(result),
+            right: Value::Literal(0),
+        },
+        None => operation,
+    }
+}
+
+#[test]
+fn unrolls_loop_into_array_literals() {
+    use crate::grammar::program_allParser;
+
+    let mut program = program_allParser::new()
+        .parse("PROGRAM IS t[1:3] BEGIN FOR i FROM 1 TO 3 DO t[i] := i * 2; ENDFOR END")
+        .unwrap();
+    unroll_program(&mut program);
+
+    assert_eq!(program.commands.len(), 3);
+    assert!(program.commands.iter().zip(1..).all(|(command, k)| matches!(
+        command,
+        Command::Assign(Identifier::ArrayLit(_, index), Operation { left: Value::Literal(value), .. })
+            if *index == k && *value == 2 * k
+    )));
+}
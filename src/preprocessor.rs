@@ -1,26 +1,81 @@
 use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Formatter};
 use std::mem;
 use crate::procedures::division::DIVISION;
 use crate::procedures::{SHIFT_LEFT, SHIFT_RIGHT};
 use crate::procedures::multiplication::MULTIPLICATION;
-use crate::structure::{Command, Condition, Declaration, Identifier, Operation, Operator, Program, Value};
+use crate::structure::{CaseBranch, Command, Condition, Declaration, Identifier, Operation, Operator, Program, Value};
 
 #[derive(Debug)]
 pub struct Preprocessor {
     pub function_counter: HashMap<String,usize>,
+    /// Number of uses placed inside loops, included in `function_counter`.
+    pub loop_uses: HashMap<String, usize>,
+    /// Whether multiplication and division by powers of two are replaced with shifts.
+    pub(crate) shifts: bool,
     found_iterators: Vec<String>,
+    /// Procedure being processed, if it is not allowed to call itself.
+    non_recursive: Option<String>,
 }
 
-#[derive(Debug)]
 pub enum StaticAnalysisError {
     UnknownFunction(String),
+    /// Procedure calls itself without being marked `RECURSIVE`.
+    Recursion(String),
+    /// Procedure without `RETURN` used as a value.
+    NoReturnValue(String),
+    /// `BREAK` or `CONTINUE` not inside a loop.
+    OutsideLoop,
+    /// `STEP` of a `FOR` loop given by a literal which is not positive.
+    NonPositiveStep(i64),
+    /// Range of `CASE` with the first value greater than the last.
+    EmptyRange(i64, i64),
+    /// Value listed in more than one range of `CASE`.
+    RepeatedCase(i64),
+    /// Array used with a number of indices different from the number of its dimensions.
+    Dimensions(String),
+    /// Literal index outside bounds of its dimension.
+    InvalidIndex(String, i64),
+    /// Name of a constant declared again as a variable, array or argument.
+    ConstantCollision(String),
+    /// Constant assigned, read into, used as an iterator or passed to a procedure.
+    ConstantModified(String),
+    /// Array bound given by a name which is not a constant.
+    UnknownConstant(String),
+    /// Global declared twice or declared again by the main program.
+    GlobalCollision(String),
+}
+
+impl Debug for StaticAnalysisError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StaticAnalysisError::UnknownFunction(name) => write!(f, "Unknown procedure {}", name),
+            StaticAnalysisError::Recursion(name) => write!(f, "Procedure {} calls itself without RECURSIVE", name),
+            StaticAnalysisError::NoReturnValue(name) => write!(f, "Procedure {} does not return a value", name),
+            StaticAnalysisError::OutsideLoop => write!(f, "BREAK or CONTINUE outside a loop"),
+            StaticAnalysisError::NonPositiveStep(step) => write!(f, "STEP {} is not positive", step),
+            StaticAnalysisError::EmptyRange(first, last) => write!(f, "Range {}..{} of CASE is empty", first, last),
+            StaticAnalysisError::RepeatedCase(value) => write!(f, "Value {} is repeated in CASE", value),
+            StaticAnalysisError::Dimensions(name) => write!(f, "Wrong number of indices of array {}", name),
+            StaticAnalysisError::InvalidIndex(name, index) => {
+                write!(f, "Index {} outside bounds of array {}", index, name)
+            }
+            StaticAnalysisError::ConstantCollision(name) => write!(f, "Constant {} declared again", name),
+            StaticAnalysisError::ConstantModified(name) => write!(f, "Constant {} is modified", name),
+            StaticAnalysisError::UnknownConstant(name) => write!(f, "Unknown constant {}", name),
+            StaticAnalysisError::GlobalCollision(name) => write!(f, "Global {} declared again", name),
+        }
+    }
 }
 
 impl Preprocessor {
     pub(crate) fn new() -> Self {
         let mut new = Preprocessor {
             function_counter: HashMap::new(),
+            loop_uses: HashMap::new(),
+            shifts: true,
             found_iterators: Vec::new(),
+            non_recursive: None,
         };
         new.function_counter.insert(MULTIPLICATION.to_string(), 0);
         new.function_counter.insert(DIVISION.to_string(), 0);
@@ -34,6 +89,7 @@ impl Preprocessor {
     pub(crate) fn process_program(&mut self, program: &mut Program) -> Result<(), StaticAnalysisError> {
         for procedure in program.procedures.iter_mut() {
             self.function_counter.insert(procedure.name.clone(), 0);
+            self.non_recursive = (!procedure.recursive).then(|| procedure.name.clone());
 
             self.process_commands(&mut procedure.commands, false)?;
 
@@ -47,6 +103,7 @@ impl Preprocessor {
             }
         }
 
+        self.non_recursive = None;
         self.process_commands(&mut program.commands, false)?;
 
         let iters = mem::take(&mut self.found_iterators);
@@ -65,41 +122,65 @@ impl Preprocessor {
         for command in commands {
             match command {
                 Command::Assign(_, operation) => {
-                    self.process_operation(operation)?;
+                    self.process_operation(operation, in_loop)?;
                 }
                 Command::If(cond, commands)  => {
                     self.process_commands(commands, in_loop)?;
-                    self.process_condition(cond);
+                    self.process_condition(cond, in_loop)?;
                 }
                 Command::While(cond, commands) | Command::Repeat(cond, commands) => {
                     self.process_commands(commands, true)?;
-                    self.process_condition(cond);
+                    self.process_condition(cond, true)?;
                 }
                 Command::IfElse(cond, commands, commands2) => {
                     self.process_commands(commands, in_loop)?;
                     self.process_commands(commands2, in_loop)?;
-                    self.process_condition(cond);
+                    self.process_condition(cond, in_loop)?;
+                }
+                Command::Case(value, branches, otherwise) => {
+                    check_ranges(branches)?;
+                    for branch in branches.iter_mut() {
+                        self.process_commands(&mut branch.commands, in_loop)?;
+                    }
+                    self.process_commands(otherwise, in_loop)?;
+                    self.process_value(value, in_loop)?;
                 }
-                Command::For(iterator, start, end, commands) | Command::ForDown(iterator, start, end, commands) => {
+                Command::For(iterator, start, end, step, commands) | Command::ForDown(iterator, start, end, step, commands) => {
                     self.found_iterators.push(iterator.clone());
                     self.found_iterators.push(format!("{}_end", iterator));
+                    match step {
+                        Value::Literal(step) if *step <= 0 => {
+                            return Err(StaticAnalysisError::NonPositiveStep(*step));
+                        }
+                        Value::Literal(_) => {}
+                        // Step given by a variable is copied when the loop starts
+                        _ => self.found_iterators.push(format!("{}_step", iterator)),
+                    }
                     self.process_commands(commands, true)?;
-                    self.process_value(start);
-                    self.process_value(end);
+                    self.process_value(start, in_loop)?;
+                    self.process_value(end, in_loop)?;
+                    self.process_value(step, in_loop)?;
                 }
                 Command::FunctionCall(name, _) => {
-                    self.add_function_use(name)?;
+                    self.add_function_use(name, in_loop)?;
+                }
+                Command::Read(_) | Command::Asm(_) => {}
+                Command::Break | Command::Continue if !in_loop => {
+                    return Err(StaticAnalysisError::OutsideLoop);
                 }
-                Command::Read(_) => {}
+                Command::Break | Command::Continue | Command::Line(_) => {}
                 Command::Write(value) => {
-                    self.process_value(value);
+                    self.process_value(value, in_loop)?;
                 }
             }
         }
         Ok(())
     }
 
-    fn process_value(&mut self, value: &Value) {
+    fn process_value(&mut self, value: &mut Value, in_loop: bool) -> Result<(), StaticAnalysisError> {
+        if let Value::Expression(operation) = value {
+            self.process_operation(operation, in_loop)?;
+        }
         // match value {
         //     Value::Literal(val) => {
         //         let counter = self.literal_counter.entry(*val).or_insert(0);
@@ -107,27 +188,34 @@ impl Preprocessor {
         //     }
         //     Value::Identifier(_) => {}
         // }
+        Ok(())
     }
 
-    fn add_function_use(&mut self, name: &str) -> Result<(), StaticAnalysisError> {
+    fn add_function_use(&mut self, name: &str, in_loop: bool) -> Result<(), StaticAnalysisError> {
         // let counter = self.function_counter.entry(name.to_string()).or_insert(0);
         // *counter += 1;
+        if self.non_recursive.as_deref() == Some(name) {
+            return Err(StaticAnalysisError::Recursion(name.to_string()));
+        }
         match self.function_counter.get_mut(name) {
             None => {
                 Err(StaticAnalysisError::UnknownFunction(name.to_string()))
             }
             Some(val) => {
                 *val += 1;
+                if in_loop {
+                    *self.loop_uses.entry(name.to_string()).or_insert(0) += 1;
+                }
                 Ok(())
             }
         }
     }
 
-    fn process_operation(&mut self, operation: &mut Operation) -> Result<(), StaticAnalysisError> {
+    fn process_operation(&mut self, operation: &mut Operation, in_loop: bool) -> Result<(), StaticAnalysisError> {
         match (&operation.left, &operation.right, &operation.operator) {
             (Value::Literal(lit), Value::Identifier(var), Operator::Multiply) |
             (Value::Identifier(var), Value::Literal(lit), Operator::Multiply) => {
-                if lit.count_ones() == 1 {
+                if self.shifts && lit.count_ones() == 1 {
                     let log = lit.trailing_zeros();
                     let mut new = Operation {
                         left: Value::Identifier(var.clone()),
@@ -138,11 +226,11 @@ impl Preprocessor {
                     let counter = self.function_counter.entry("@shift_left".to_string()).or_insert(0);
                     *counter += log as usize;
                 } else {
-                    self.add_function_use(MULTIPLICATION)?;
+                    self.add_function_use(MULTIPLICATION, in_loop)?;
                 }
             }
             (Value::Identifier(var), Value::Literal(lit), Operator::Divide) => {
-                if lit.count_ones() == 1 {
+                if self.shifts && lit.count_ones() == 1 {
                     let log = lit.trailing_zeros();
                     let mut new = Operation {
                         left: Value::Identifier(var.clone()),
@@ -153,29 +241,49 @@ impl Preprocessor {
                     let counter = self.function_counter.entry("@shift_right".to_string()).or_insert(0);
                     *counter += log as usize;
                 } else {
-                    self.add_function_use(DIVISION)?;
+                    self.add_function_use(DIVISION, in_loop)?;
                 }
             },
             (_,_,Operator::Multiply) => {
-                self.add_function_use(MULTIPLICATION)?;
+                self.add_function_use(MULTIPLICATION, in_loop)?;
             }
             (_,_,Operator::Divide) => {
-                self.add_function_use(DIVISION)?;
+                self.add_function_use(DIVISION, in_loop)?;
             }
             (_,_,Operator::Modulo) => {
-                self.add_function_use(DIVISION)?;
+                self.add_function_use(DIVISION, in_loop)?;
             }
             _ => {}
         }
-        self.process_value(&operation.left);
-        self.process_value(&operation.right);
+        self.process_value(&mut operation.left, in_loop)?;
+        self.process_value(&mut operation.right, in_loop)?;
+
+        Ok(())
+    }
 
+    fn process_condition(&mut self, condition: &mut Condition, in_loop: bool) -> Result<(), StaticAnalysisError> {
+        for comparison in condition.comparisons_mut() {
+            self.process_commands(&mut comparison.before, in_loop)?;
+            self.process_value(&mut comparison.left, in_loop)?;
+            self.process_value(&mut comparison.right, in_loop)?;
+        }
         Ok(())
     }
+}
 
-    fn process_condition(&mut self, condition: &mut Condition) {
-        self.process_value(&condition.left);
-        self.process_value(&condition.right);
+/// Checks that ranges of `CASE` are not empty and do not overlap.
+fn check_ranges(branches: &[CaseBranch]) -> Result<(), StaticAnalysisError> {
+    let mut ranges: Vec<(i64, i64)> = branches
+        .iter()
+        .flat_map(|branch| branch.ranges.iter().copied())
+        .collect();
+    if let Some((first, last)) = ranges.iter().find(|(first, last)| first > last) {
+        return Err(StaticAnalysisError::EmptyRange(*first, *last));
+    }
+    ranges.sort();
+    match ranges.windows(2).find(|pair| pair[1].0 <= pair[0].1) {
+        Some(pair) => Err(StaticAnalysisError::RepeatedCase(pair[1].0)),
+        None => Ok(()),
     }
 }
 
@@ -187,6 +295,7 @@ fn teest(){
         operator: Operator::Divide,
     };
     let mut preprocessor = Preprocessor::new();
-    let _ = preprocessor.process_operation(&mut op);
+    let _ = preprocessor.process_operation(&mut op, false);
     println!("{:?}", op);
-}
\ No newline at end of file
+}
+
@@ -1,8 +1,10 @@
+use crate::intermediate::scratch::SCRATCH_END;
 use crate::intermediate::{Instruction, InstructionFactory, TranslationError};
+use crate::optimizer::pipeline::Options;
 use crate::procedures::FunctionRepository;
 use crate::procedures::{function_return, function_start, ProcedureHandler};
 use crate::structure::{Declaration, Identifier, Value};
-use crate::variables::VariableDictionary;
+use crate::variables::{Pointer, VariableDictionary};
 
 pub struct AssemblyProcedure {
     name: &'static str,
@@ -27,6 +29,7 @@ impl ProcedureHandler for AssemblyProcedure {
         &mut self,
         variable_stack: usize,
         instruction_start: usize,
+        options: Options,
         _function_repository: &mut FunctionRepository,
     ) -> Result<(InstructionFactory, usize), TranslationError> {
         self.inline = false;
@@ -34,6 +37,7 @@ impl ProcedureHandler for AssemblyProcedure {
         dictionary.add(Declaration::VariableDecl(function_return(self.name)))?;
 
         let mut instructions = InstructionFactory::new(self.name.to_string(), instruction_start);
+        instructions.options = options;
         instructions.set_label(function_start(self.name));
 
         (self.procedure_factory)(&mut instructions);
@@ -41,7 +45,7 @@ impl ProcedureHandler for AssemblyProcedure {
         let ret = dictionary.write(Value::Identifier(Identifier::Variable(function_return(
             self.name
         ))))?;
-        let ret = instructions.prepare_pointer(ret, 2);
+        let ret = instructions.prepare_pointer(ret);
         instructions.push(Instruction::Return(ret));
 
         let stack = dictionary.where_we_finished();
@@ -71,7 +75,7 @@ impl ProcedureHandler for AssemblyProcedure {
                 let ret = self.variable_dictionary.as_mut().unwrap().write(Value::Identifier(Identifier::Variable(
                     function_return(self.name),
                 )))?;
-                let ret = instructions.prepare_pointer(ret, 9);
+                let ret = instructions.prepare_pointer(ret);
 
                 instructions.action_stack.push("set return".to_string());
                 instructions.push(Instruction::LoadKPlus3);
@@ -83,4 +87,31 @@ impl ProcedureHandler for AssemblyProcedure {
             }
         }
     }
+
+    fn size(&self) -> usize {
+        let mut instructions = InstructionFactory::new(self.name.to_string(), 0);
+        (self.procedure_factory)(&mut instructions);
+        instructions.instructions.len()
+    }
+
+    fn arguments(&self) -> usize {
+        0
+    }
+
+    fn clobbers(&self) -> Vec<usize> {
+        let mut instructions = InstructionFactory::new(self.name.to_string(), 0);
+        (self.procedure_factory)(&mut instructions);
+        let mut cells: Vec<usize> = instructions
+            .instructions
+            .iter()
+            .filter_map(|line| match line.instruction {
+                Instruction::Store(Pointer::Cell(cell)) | Instruction::Get(Pointer::Cell(cell)) => Some(cell),
+                _ => None,
+            })
+            .filter(|cell| (1..SCRATCH_END).contains(cell))
+            .collect();
+        cells.sort_unstable();
+        cells.dedup();
+        cells
+    }
 }
@@ -1,82 +1,108 @@
-use crate::intermediate::{Instruction, InstructionFactory};
-use crate::variables::Pointer;
+use crate::intermediate::scratch::{ARGUMENT_LEFT, ARGUMENT_RIGHT, REMAINDER, RESULT};
+use crate::intermediate::InstructionFactory;
 
 pub const DIVISION: &str = "@division";
 
-pub fn division_procedure(instr: &mut InstructionFactory) {
-    instr.push(Instruction::Subtr(Pointer::Cell(0)));
-    instr.push(Instruction::Store(Pointer::Cell(4)));
-
-    instr.push(Instruction::Load(Pointer::Cell(6)));
-    instr.push(Instruction::Jzero(64));
-    instr.push(Instruction::Jpos(3));
-    instr.push(Instruction::Subtr(Pointer::Cell(0)));
-    instr.push(Instruction::Subtr(Pointer::Cell(6)));
-    instr.push(Instruction::Store(Pointer::Cell(2)));
-
-    instr.push(Instruction::Load(Pointer::Cell(7)));
-    instr.push(Instruction::Jzero(2));
-    instr.push(Instruction::Jump(4));
-    instr.push(Instruction::Subtr(Pointer::Cell(0)));
-    instr.push(Instruction::Store(Pointer::Cell(2)));
-    instr.push(Instruction::Jump(54));
-    instr.push(Instruction::Jpos(3));
-    instr.push(Instruction::Subtr(Pointer::Cell(0)));
-    instr.push(Instruction::Subtr(Pointer::Cell(7)));
-    instr.push(Instruction::Store(Pointer::Cell(3)));
+/// Dividend and divisor are taken from the argument cells,
+/// the quotient is left in the result cell and the remainder in the remainder cell.
+const CELLS: [(&str, usize); 6] = [
+    ("dividend", ARGUMENT_LEFT),
+    ("divisor", ARGUMENT_RIGHT),
+    ("quotient", RESULT),
+    ("remainder", REMAINDER),
+    ("shifted", 3),
+    ("bit", 5),
+];
 
-    instr.push(Instruction::Subtr(Pointer::Cell(2)));
-    instr.push(Instruction::Jpos(29));
+const CODE: &str = "
+        SUB 0
+        STORE quotient
+        LOAD dividend
+        JZERO by_zero           # remainder of zero is zero too
+        JPOS dividend_positive
+        SUB 0
+        SUB dividend
+    dividend_positive:
+        STORE remainder         # |dividend|
+        LOAD divisor
+        JZERO by_zero
+        JUMP divisor_nonzero
+    by_zero:
+        SUB 0
+        STORE remainder
+        JUMP end
+    divisor_nonzero:
+        JPOS divisor_positive
+        SUB 0
+        SUB divisor
+    divisor_positive:
+        STORE shifted           # |divisor|
+        SUB remainder
+        JPOS signs
 
-    instr.push(Instruction::Load(Pointer::Literal(1)));
-    instr.push(Instruction::Store(Pointer::Cell(5)));
+        LOAD =1
+        STORE bit
+    grow:
+        LOAD shifted
+        ADD 0
+        SUB remainder
+        JPOS subtract
+        ADD remainder
+        STORE shifted
+        LOAD bit
+        ADD bit
+        STORE bit
+    again:
+        JUMP grow
 
-    instr.push(Instruction::Load(Pointer::Cell(3)));
-    instr.push(Instruction::Add(Pointer::Cell(0)));
-    instr.push(Instruction::Subtr(Pointer::Cell(2)));
-    instr.push(Instruction::Jpos(7));
-    instr.push(Instruction::Add(Pointer::Cell(2)));
-    instr.push(Instruction::Store(Pointer::Cell(3)));
-    instr.push(Instruction::Load(Pointer::Cell(5)));
-    instr.push(Instruction::Add(Pointer::Cell(5)));
-    instr.push(Instruction::Store(Pointer::Cell(5)));
-    instr.push(Instruction::Jump(-9));
+    subtract:
+        LOAD remainder
+        JZERO signs
+        SUB shifted
+        JNEG smaller
+        STORE remainder
+        LOAD quotient
+        ADD bit
+        STORE quotient
+    smaller:
+        LOAD bit
+        HALF
+        JZERO signs
+        STORE bit
+        LOAD shifted
+        HALF
+        STORE shifted
+        JUMP again
 
-    instr.push(Instruction::Load(Pointer::Cell(2)));
-    instr.push(Instruction::Jzero(15));
-    instr.push(Instruction::Subtr(Pointer::Cell(3)));
-    instr.push(Instruction::Jneg(5));
-    instr.push(Instruction::Store(Pointer::Cell(2)));
-    instr.push(Instruction::Load(Pointer::Cell(4)));
-    instr.push(Instruction::Add(Pointer::Cell(5)));
-    instr.push(Instruction::Store(Pointer::Cell(4)));
-    instr.push(Instruction::Load(Pointer::Cell(5)));
-    instr.push(Instruction::Half);
-    instr.push(Instruction::Jzero(6));
-    instr.push(Instruction::Store(Pointer::Cell(5)));
-    instr.push(Instruction::Load(Pointer::Cell(3)));
-    instr.push(Instruction::Half);
-    instr.push(Instruction::Store(Pointer::Cell(3)));
-    instr.push(Instruction::Jump(-16));
-
-    instr.push(Instruction::Load(Pointer::Cell(6)));
-    instr.push(Instruction::Jpos(7));
-    instr.push(Instruction::Subtr(Pointer::Cell(0)));
-    instr.push(Instruction::Subtr(Pointer::Cell(2)));
-    instr.push(Instruction::Store(Pointer::Cell(2)));
-    instr.push(Instruction::Load(Pointer::Cell(7)));
-    instr.push(Instruction::Jpos(4));
-    instr.push(Instruction::Jump(12));
-    instr.push(Instruction::Load(Pointer::Cell(7)));
-    instr.push(Instruction::Jpos(10));
-    instr.push(Instruction::Load(Pointer::Literal(1)));
-    instr.push(Instruction::Add(Pointer::Cell(4)));
-    instr.push(Instruction::Store(Pointer::Cell(4)));
-    instr.push(Instruction::Subtr(Pointer::Cell(0)));
-    instr.push(Instruction::Subtr(Pointer::Cell(4)));
-    instr.push(Instruction::Store(Pointer::Cell(4)));
-    instr.push(Instruction::Load(Pointer::Cell(2)));
-    instr.push(Instruction::Add(Pointer::Cell(7)));
-    instr.push(Instruction::Store(Pointer::Cell(2)));
+    signs:
+        LOAD dividend
+        JPOS dividend_was_positive
+        SUB 0
+        SUB remainder
+        STORE remainder
+        LOAD divisor
+        JPOS round_down
+        JUMP end
+    dividend_was_positive:
+        LOAD divisor
+        JPOS end
+    round_down:                 # quotient is rounded down unless the division is exact
+        LOAD remainder
+        JZERO negate
+        ADD divisor
+        STORE remainder
+        LOAD =1
+        ADD quotient
+        STORE quotient
+    negate:
+        SUB 0
+        SUB quotient
+        STORE quotient
+    end:
+";
 
+pub fn division_procedure(instr: &mut InstructionFactory) {
+    instr
+        .assemble(CODE, &CELLS)
+        .expect("Invalid division code");
 }
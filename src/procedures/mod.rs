@@ -1,5 +1,7 @@
 use std::collections::HashMap;
+use crate::intermediate::scratch::SCRATCH_END;
 use crate::intermediate::{InstructionFactory, TranslationError};
+use crate::optimizer::pipeline::Options;
 use crate::procedures::assembly::AssemblyProcedure;
 use crate::procedures::division::{division_procedure, DIVISION};
 use crate::procedures::multiplication::{multiplication_procedure, MULTIPLICATION};
@@ -7,6 +9,7 @@ use crate::variables::VariableDictionary;
 
 pub mod division;
 pub mod assembly;
+pub mod recursive;
 pub mod regular;
 mod swap_vars;
 pub mod multiplication;
@@ -27,6 +30,7 @@ pub trait ProcedureHandler {
         &mut self,
         variable_stack: usize,
         instruction_start: usize,
+        options: Options,
         function_repository: &mut FunctionRepository,
     ) -> Result<(InstructionFactory, usize), TranslationError>;
     fn call(
@@ -36,6 +40,28 @@ pub trait ProcedureHandler {
         instructions: &mut InstructionFactory,
         function_repository: &mut FunctionRepository,
     ) -> Result<(), TranslationError>;
+
+    /// Estimated number of instructions in the procedure body.
+    fn size(&self) -> usize;
+    /// Number of arguments copied on every call.
+    fn arguments(&self) -> usize;
+    /// Inlining forced or forbidden by the programmer.
+    fn inline_hint(&self) -> Option<bool> {
+        None
+    }
+    /// Whether values of local variables can be read in the next call,
+    /// so every call has to use the same variables.
+    fn keeps_state(&self) -> bool {
+        false
+    }
+    /// Whether the procedure can call itself, so it can not be inlined.
+    fn recursive(&self) -> bool {
+        false
+    }
+    /// Scratch cells which can be overwritten by a call.
+    fn clobbers(&self) -> Vec<usize> {
+        (1..SCRATCH_END).collect()
+    }
 }
 
 pub fn function_start(name: &str) -> String {
@@ -67,6 +93,7 @@ impl ProcedureHandler for DummyProcedure {
         &mut self,
         _variable_stack: usize,
         _instruction_start: usize,
+        _options: Options,
         _function_repository: &mut FunctionRepository,
     ) -> Result<
         (InstructionFactory, usize),
@@ -84,4 +111,12 @@ impl ProcedureHandler for DummyProcedure {
     ) -> Result<(), TranslationError> {
         panic!("This should never be called");
     }
+
+    fn size(&self) -> usize {
+        panic!("This should never be called");
+    }
+
+    fn arguments(&self) -> usize {
+        panic!("This should never be called");
+    }
 }
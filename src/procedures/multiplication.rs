@@ -1,52 +1,72 @@
-use crate::intermediate::{Instruction, InstructionFactory};
-use crate::variables::Pointer;
+use crate::intermediate::scratch::{ARGUMENT_LEFT, ARGUMENT_RIGHT, RESULT};
+use crate::intermediate::InstructionFactory;
 
 pub const MULTIPLICATION: &str = "@multiplication";
 
-pub fn multiplication_procedure(instr: &mut InstructionFactory) {
-    instr.push(Instruction::Subtr(Pointer::Cell(0)));
-    instr.push(Instruction::Store(Pointer::Cell(4)));
-
-    instr.push(Instruction::Load(Pointer::Cell(6)));
-    instr.push(Instruction::Jzero(37));
-    instr.push(Instruction::Jpos(3));
-    instr.push(Instruction::Subtr(Pointer::Cell(0)));
-    instr.push(Instruction::Subtr(Pointer::Cell(6)));
-    instr.push(Instruction::Store(Pointer::Cell(2)));
+/// Factors are taken from the argument cells, the product is left in the result cell.
+const CELLS: [(&str, usize); 5] = [
+    ("left", ARGUMENT_LEFT),
+    ("right", ARGUMENT_RIGHT),
+    ("product", RESULT),
+    ("doubled", 2),
+    ("halved", 3),
+];
 
-    instr.push(Instruction::Load(Pointer::Cell(7)));
-    instr.push(Instruction::Jzero(31));
-    instr.push(Instruction::Jpos(3));
-    instr.push(Instruction::Subtr(Pointer::Cell(0)));
-    instr.push(Instruction::Subtr(Pointer::Cell(7)));
-    instr.push(Instruction::Store(Pointer::Cell(3)));
+const CODE: &str = "
+        SUB 0
+        STORE product
+        LOAD left
+        JZERO end
+        JPOS left_positive
+        SUB 0
+        SUB left
+    left_positive:
+        STORE doubled           # |left|
+        LOAD right
+        JZERO end
+        JPOS right_positive
+        SUB 0
+        SUB right
+    right_positive:
+        STORE halved            # |right|
 
-    instr.push(Instruction::Load(Pointer::Cell(3)));
-    instr.push(Instruction::Half);
-    instr.push(Instruction::Add(Pointer::Cell(0)));
-    instr.push(Instruction::Subtr(Pointer::Cell(3)));
-    instr.push(Instruction::Jzero(4));
-    instr.push(Instruction::Load(Pointer::Cell(4)));
-    instr.push(Instruction::Add(Pointer::Cell(2)));
-    instr.push(Instruction::Store(Pointer::Cell(4)));
-    instr.push(Instruction::Load(Pointer::Cell(3)));
-    instr.push(Instruction::Half);
-    instr.push(Instruction::Jzero(6));
-    instr.push(Instruction::Store(Pointer::Cell(3)));
-    instr.push(Instruction::Load(Pointer::Cell(2)));
-    instr.push(Instruction::Add(Pointer::Cell(2)));
-    instr.push(Instruction::Store(Pointer::Cell(2)));
-    instr.push(Instruction::Jump(-15));
+    multiply:
+        LOAD halved
+        HALF
+        ADD 0
+        SUB halved
+        JZERO even
+        LOAD product
+        ADD doubled
+        STORE product
+    even:
+        LOAD halved
+        HALF
+        JZERO signs
+        STORE halved
+        LOAD doubled
+        ADD doubled
+        STORE doubled
+        JUMP multiply
 
-    instr.push(Instruction::Load(Pointer::Cell(6)));
-    instr.push(Instruction::Jneg(4));
-    instr.push(Instruction::Load(Pointer::Cell(7)));
-    instr.push(Instruction::Jneg(4));
-    instr.push(Instruction::Jump(6));
-    instr.push(Instruction::Load(Pointer::Cell(7)));
-    instr.push(Instruction::Jneg(4));
-    instr.push(Instruction::Subtr(Pointer::Cell(0)));
-    instr.push(Instruction::Subtr(Pointer::Cell(4)));
-    instr.push(Instruction::Store(Pointer::Cell(4)));
+    signs:
+        LOAD left
+        JNEG left_negative
+        LOAD right
+        JNEG negate
+        JUMP end
+    left_negative:
+        LOAD right
+        JNEG end
+    negate:
+        SUB 0
+        SUB product
+        STORE product
+    end:
+";
 
+pub fn multiplication_procedure(instr: &mut InstructionFactory) {
+    instr
+        .assemble(CODE, &CELLS)
+        .expect("Invalid multiplication code");
 }
@@ -0,0 +1,228 @@
+use crate::intermediate::TranslationError::ErrorWithLocation;
+use crate::intermediate::{Instruction, InstructionFactory, TranslationError};
+use crate::optimizer::cost::estimate_size;
+use crate::optimizer::pipeline::Options;
+use crate::procedures::{function_return, function_start, FunctionRepository, ProcedureHandler};
+use crate::structure::{ArgumentDecl, Command, Declaration, Identifier, Procedure, Value};
+use crate::variables::{FrameSlot, Pointer, Type, VariableDictionary};
+use std::mem;
+
+/// Slot of the frame holding address of the frame of the caller.
+const CALLER_FRAME: usize = 1;
+/// Slot of the frame holding the first argument, slot 0 holds the return address.
+const FIRST_ARGUMENT: usize = 2;
+
+/// Procedure marked `RECURSIVE`, every call gets a new frame on the stack.
+///
+/// The stack pointer cell holds address of the first cell above all frames,
+/// the caller puts the return address and addresses of arguments at its top.
+/// Arguments and local variables are reached through register cells
+/// set for the current frame on entry and again for the frame of the caller on return.
+pub struct RecursiveProcedure {
+    name: String,
+    arguments: Vec<ArgumentDecl>,
+    variables: Vec<Declaration>,
+    commands: Vec<Command>,
+    stack: usize,
+}
+
+impl ProcedureHandler for RecursiveProcedure {
+    fn initialize(
+        &mut self,
+        variable_stack: usize,
+        instruction_start: usize,
+        options: Options,
+        function_repository: &mut FunctionRepository,
+    ) -> Result<(InstructionFactory, usize), TranslationError> {
+        let mut dictionary = VariableDictionary::new(variable_stack);
+        let mut translator = InstructionFactory::new(format!("Procedure {}", self.name), instruction_start);
+        translator.options = options;
+
+        if let Err(error) = self.construct_function(function_repository, &mut dictionary, &mut translator) {
+            return Err(ErrorWithLocation(format!("{:?}", error), translator.action_stack));
+        }
+
+        Ok((translator, dictionary.where_we_finished()))
+    }
+
+    fn call(
+        &mut self,
+        arguments: Vec<String>,
+        variable_dictionary: &mut VariableDictionary,
+        instructions: &mut InstructionFactory,
+        _function_repository: &mut FunctionRepository,
+    ) -> Result<(), TranslationError> {
+        for (slot, (provided, declared)) in (FIRST_ARGUMENT..).zip(arguments.iter().zip(self.arguments.iter())) {
+            instructions
+                .action_stack
+                .push(format!("{} -> frame slot {}", provided, slot));
+
+            let place = Type::Array(Pointer::Cell(self.stack), Pointer::Literal(slot as i64));
+            let place = instructions.prepare_pointer(place);
+            match declared {
+                ArgumentDecl::VariableArg(_) => {
+                    let value = variable_dictionary
+                        .write(Value::Identifier(Identifier::Variable(provided.clone())))?;
+                    let value = instructions.prepare_pointer(value);
+                    instructions.push(Instruction::Load(value.location()));
+                    instructions.release_pointer(value);
+                }
+                ArgumentDecl::ArrayArg(_) | ArgumentDecl::MatrixArg(_, _) => {
+                    let offset = variable_dictionary.get_array_offset(provided)?;
+                    instructions.push(Instruction::Load(offset));
+                }
+            }
+            instructions.push(Instruction::Store(place));
+            instructions.release_pointer(place);
+            instructions.action_stack.pop();
+        }
+
+        instructions.action_stack.push("set return".to_string());
+        instructions.push(Instruction::LoadKPlus3);
+        instructions.push(Instruction::Store(Pointer::IndirectCell(self.stack)));
+        instructions.action_stack.pop();
+
+        instructions.push(Instruction::Goto(function_start(&self.name)));
+        Ok(())
+    }
+
+    fn size(&self) -> usize {
+        estimate_size(&self.commands)
+    }
+
+    fn arguments(&self) -> usize {
+        self.arguments.len()
+    }
+
+    fn recursive(&self) -> bool {
+        true
+    }
+}
+
+impl RecursiveProcedure {
+    /// `stack` is the cell of the stack pointer.
+    pub fn new(procedure: Procedure, stack: usize) -> Self {
+        RecursiveProcedure {
+            name: procedure.name,
+            arguments: procedure.arguments,
+            variables: procedure.declarations,
+            commands: procedure.commands,
+            stack,
+        }
+    }
+
+    fn construct_function(
+        &mut self,
+        function_repository: &mut FunctionRepository,
+        dictionary: &mut VariableDictionary,
+        translator: &mut InstructionFactory,
+    ) -> Result<(), TranslationError> {
+        // Calls inside the body only need to know where to put the arguments
+        let declaration = RecursiveProcedure {
+            name: self.name.clone(),
+            arguments: self.arguments.clone(),
+            variables: vec![],
+            commands: vec![],
+            stack: self.stack,
+        };
+        function_repository.insert(self.name.clone(), Box::new(declaration));
+
+        let ret = static_cell(dictionary, function_return(&self.name))?;
+        let frame = static_cell(dictionary, format!("@frame@{}", self.name))?;
+
+        dictionary.use_frame(FIRST_ARGUMENT);
+        for argument in &self.arguments {
+            dictionary.add_argument(argument.clone())?;
+        }
+        for declaration in &self.variables {
+            dictionary.add(declaration.clone())?;
+        }
+
+        // Size of the frame is known after translating the body, which can declare new variables
+        let setup = translator.reserve_label("frame setup");
+        let body = translator.reserve_label("body");
+        translator.set_label(function_start(&self.name));
+        translator.push(Instruction::Goto(setup.clone()));
+        translator.set_label(body.clone());
+        translator.translate_commands(mem::take(&mut self.commands), dictionary, function_repository)?;
+
+        let layout = dictionary.frame().unwrap();
+        let stack = Pointer::Cell(self.stack);
+
+        translator.action_stack.push("return".to_string());
+        translator.push(Instruction::Load(Pointer::Cell(frame)));
+        translator.push(Instruction::Store(stack));
+        translator.push(Instruction::Load(Pointer::IndirectCell(frame)));
+        translator.push(Instruction::Store(Pointer::Cell(ret)));
+        translator.push(Instruction::Load(Pointer::Cell(frame)));
+        translator.push(Instruction::Add(Pointer::Literal(CALLER_FRAME as i64)));
+        translator.push(Instruction::Load(Pointer::IndirectCell(0)));
+        translator.push(Instruction::Store(Pointer::Cell(frame)));
+        // When the caller is not this procedure the registers are not used
+        set_registers(translator, frame, &layout.registers);
+        translator.push(Instruction::Return(Pointer::Cell(ret)));
+        translator.action_stack.pop();
+
+        translator.set_label(setup);
+        translator.action_stack.push("frame setup".to_string());
+        translator.push(Instruction::Load(stack));
+        translator.push(Instruction::Add(Pointer::Literal(CALLER_FRAME as i64)));
+        translator.push(Instruction::Store(Pointer::Cell(ret)));
+        translator.push(Instruction::Load(Pointer::Cell(frame)));
+        translator.push(Instruction::Store(Pointer::IndirectCell(ret)));
+        translator.push(Instruction::Load(stack));
+        translator.push(Instruction::Store(Pointer::Cell(frame)));
+        translator.push(Instruction::Add(Pointer::Literal(layout.size as i64)));
+        translator.push(Instruction::Store(stack));
+        set_registers(translator, frame, &layout.registers);
+        translator.push(Instruction::Goto(body));
+        translator.action_stack.pop();
+        Ok(())
+    }
+}
+
+fn static_cell(dictionary: &mut VariableDictionary, name: String) -> Result<usize, TranslationError> {
+    dictionary.add(Declaration::VariableDecl(name.clone()))?;
+    match dictionary.write(Value::Identifier(Identifier::Variable(name)))? {
+        Type::Variable(Pointer::Cell(cell)) => Ok(cell),
+        _ => panic!("Static variable is not a cell"),
+    }
+}
+
+/// Points registers at arguments and variables in the frame.
+fn set_registers(translator: &mut InstructionFactory, frame: usize, registers: &[(usize, FrameSlot)]) {
+    for (register, slot) in registers {
+        translator.push(Instruction::Load(Pointer::Cell(frame)));
+        match slot {
+            FrameSlot::Argument(slot) => {
+                translator.push(Instruction::Add(Pointer::Literal(*slot as i64)));
+                translator.push(Instruction::Load(Pointer::IndirectCell(0)));
+            }
+            FrameSlot::Local(0) => {}
+            FrameSlot::Local(offset) => {
+                translator.push(Instruction::Add(Pointer::Literal(*offset)));
+            }
+        }
+        translator.push(Instruction::Store(Pointer::Cell(*register)));
+    }
+}
+
+#[test]
+fn keeps_variables_of_every_call_in_its_frame() {
+    use crate::vm::compile_and_run;
+
+    let program = "RECURSIVE PROCEDURE fib(n, r, T calls) IS a, b, m BEGIN
+            calls[0] := calls[0] + 1;
+            IF n < 2 THEN r := n; ELSE
+                m := n - 1; fib(m, a, calls);
+                m := n - 2; fib(m, b, calls);
+                r := a + b;
+            ENDIF
+        END
+        PROGRAM IS n, r, calls[0:0] BEGIN
+            READ n; calls[0] := 0; fib(n, r, calls); WRITE r; WRITE calls[0];
+        END";
+    for level in ["0", "2"] {
+        assert_eq!(compile_and_run(program, level, &[10]), vec![55, 177]);
+    }
+}
@@ -1,5 +1,8 @@
 use crate::intermediate::TranslationError::{ErrorWithLocation};
 use crate::intermediate::{Instruction, InstructionFactory, TranslationError};
+use crate::optimizer::cost::estimate_size;
+use crate::optimizer::dead_code::live_at_entry;
+use crate::optimizer::pipeline::Options;
 use crate::procedures::swap_vars::swap_commands;
 use crate::procedures::{function_return, function_start, FunctionRepository, ProcedureHandler};
 use crate::structure::{ArgumentDecl, Command, Declaration, Identifier, Procedure, Value};
@@ -11,6 +14,9 @@ use std::mem;
 
 pub struct RegularProcedure {
     inline: bool,
+    inline_hint: Option<bool>,
+    /// Number of times the procedure was inlined, used to give variables unique names.
+    instances: usize,
     name: String,
     arguments: Vec<ArgumentDecl>,
     variables: Vec<Declaration>,
@@ -23,11 +29,13 @@ impl ProcedureHandler for RegularProcedure {
         &mut self,
         variable_stack: usize,
         instruction_start: usize,
+        options: Options,
         mut function_repository: &mut FunctionRepository,
     ) -> Result<(InstructionFactory, usize), TranslationError> {
         self.inline = false;
         let mut dictionary = VariableDictionary::new(variable_stack);
         let mut translator = InstructionFactory::new(format!("Procedure {}", self.name), instruction_start);
+        translator.options = options;
 
         match self.construct_function(&mut function_repository, &mut dictionary, &mut translator) {
             Ok(ok) => ok,
@@ -90,6 +98,30 @@ impl ProcedureHandler for RegularProcedure {
         }
         Ok(())
     }
+
+    fn size(&self) -> usize {
+        estimate_size(&self.commands)
+    }
+
+    fn arguments(&self) -> usize {
+        self.arguments.len()
+    }
+
+    fn inline_hint(&self) -> Option<bool> {
+        self.inline_hint
+    }
+
+    fn keeps_state(&self) -> bool {
+        let live = live_at_entry(&self.commands);
+        self.variables.iter().any(|declaration| match declaration {
+            Declaration::VariableDecl(name) => live.contains(name),
+            // Elements are not tracked separately, any of them could be left from previous call
+            Declaration::ArrayDecl(_, _, _) | Declaration::MatrixDecl(_, _) | Declaration::NamedBoundsDecl(_, _) => {
+                true
+            }
+            Declaration::ConstantDecl(_) => false,
+        })
+    }
 }
 
 impl RegularProcedure {
@@ -99,10 +131,15 @@ impl RegularProcedure {
             arguments,
             declarations,
             commands,
+            inline,
+            recursive: _,
+            returns: _,
         } = procedure;
 
         RegularProcedure {
             inline: true,
+            inline_hint: inline,
+            instances: 0,
             name,
             arguments,
             variables: declarations,
@@ -122,7 +159,7 @@ impl RegularProcedure {
             function_return(&self.name),
         )))?;
 
-        let ret = instructions.prepare_pointer(ret, 2);
+        let ret = instructions.prepare_pointer(ret);
 
         for (provided, declared) in arguments.iter().zip(self.arguments.iter()) {
             match declared {
@@ -134,18 +171,18 @@ impl RegularProcedure {
                     let value = variable_dictionary
                         .write(Value::Identifier(Identifier::Variable(provided.clone())))?;
 
-                    let value = instructions.prepare_pointer(value, 2);
+                    let value = instructions.prepare_pointer(value);
 
                     let place = self_dictionary
                         .write(Value::Identifier(Identifier::Variable(name.clone())))?;
 
-                    let place = instructions.prepare_pointer(place, 3);
+                    let place = instructions.prepare_pointer(place);
 
                     instructions.push(Instruction::Load(value.location()));
                     instructions.push(Instruction::Store(place.location()));
                     instructions.action_stack.pop();
                 }
-                ArgumentDecl::ArrayArg(name) => {
+                ArgumentDecl::ArrayArg(name) | ArgumentDecl::MatrixArg(name, _) => {
                     instructions
                         .action_stack
                         .push(format!("arr_{} -> arr_arg_{}", provided, name));
@@ -181,7 +218,9 @@ impl RegularProcedure {
 
         for (declared, provided) in self.arguments.iter().zip(arguments.iter()) {
             match declared {
-                ArgumentDecl::VariableArg(name) | ArgumentDecl::ArrayArg(name) => {
+                ArgumentDecl::VariableArg(name)
+                | ArgumentDecl::ArrayArg(name)
+                | ArgumentDecl::MatrixArg(name, _) => {
                     variable_map
                         .insert(name.clone(), provided.clone())
                         .map_or(Ok(()), |x| Err(TranslationError::VariableError(VariableCollision(x))))?;
@@ -191,7 +230,7 @@ impl RegularProcedure {
                 ArgumentDecl::VariableArg(arg) => {
                     variable_dictionary.write(Value::Identifier(Identifier::Variable(provided.clone())))?;
                 }
-                ArgumentDecl::ArrayArg(name) => {
+                ArgumentDecl::ArrayArg(_) | ArgumentDecl::MatrixArg(_, _) => {
                     variable_dictionary.get_array_offset(provided)?;
                 }
             }
@@ -203,39 +242,34 @@ impl RegularProcedure {
             .action_stack
             .push(format!("Inlined Procedure {}", self.name));
 
-        for mut variable in self.variables.iter_mut() {
-            let old_name;
-            let new_name;
+        let prefix = match self.instances {
+            0 => format!("@{}@", self.name),
+            instance => format!("@{}@{}@", self.name, instance),
+        };
+        self.instances += 1;
+
+        for variable in self.variables.iter() {
+            let mut variable = variable.clone();
             match &mut variable {
-                Declaration::ConstantDecl(name) | Declaration::VariableDecl(name) => {
-                    old_name = name.clone();
-                    new_name = format!("@{}@{}", self.name, name);
-                    *name = new_name.clone();
-                }
-                Declaration::ArrayDecl(name, _, _) => {
-                    old_name = name.clone();
-                    new_name = format!("@{}@{}", self.name, name);
-                    *name = new_name.clone();
+                Declaration::ConstantDecl(name)
+                | Declaration::VariableDecl(name)
+                | Declaration::ArrayDecl(name, _, _)
+                | Declaration::MatrixDecl(name, _)
+                | Declaration::NamedBoundsDecl(name, _) => {
+                    let new_name = format!("{}{}", prefix, name);
+                    variable_map
+                        .insert(name.clone(), new_name.clone())
+                        .map_or(Ok(()), |x| Err(TranslationError::VariableError(VariableCollision(x))))?;
+                    *name = new_name;
                 }
             }
-            variable_map
-                .insert(old_name.clone(), new_name)
-                .map_or(Ok(()), |x| Err(TranslationError::VariableError(VariableCollision(x))))?;
-            variable_dictionary.add(variable.clone())?;
+            variable_dictionary.add(variable)?;
         }
 
-        // println!("{:?}", self.variables);
-
-        swap_commands(&mut self.commands, &variable_map)?;
-
-
-
-        instructions.translate_commands(
-            mem::take(&mut self.commands),
-            variable_dictionary,
-            function_repository,
-        )?;
+        let mut commands = self.commands.clone();
+        swap_commands(&mut commands, variable_map)?;
 
+        instructions.translate_commands(commands, variable_dictionary, function_repository)?;
 
         Ok(())
     }
@@ -260,7 +294,7 @@ impl RegularProcedure {
         let ret = dictionary.write(Value::Identifier(Identifier::Variable(function_return(
             &self.name,
         ))))?;
-        let ret = translator.prepare_pointer(ret, 2);
+        let ret = translator.prepare_pointer(ret);
         translator.push(Instruction::Return(ret));
         Ok(())
     }
@@ -1,5 +1,5 @@
 use crate::intermediate::TranslationError;
-use crate::structure::{Command, Condition, Identifier, Operation, Value};
+use crate::structure::{AsmLine, AsmOperand, Command, Condition, Identifier, Operation, Value};
 use std::collections::HashMap;
 
 pub fn swap_commands(commands: &mut Vec<Command>, variable_map: &HashMap<String, String>) -> Result<(), TranslationError> {
@@ -10,37 +10,42 @@ pub fn swap_commands(commands: &mut Vec<Command>, variable_map: &HashMap<String,
                 swap_values(left, variable_map)?;
                 swap_values(right, variable_map)?;
             }
-            Command::If(Condition{ operator, left, right }, commands) => {
-                swap_values(left, variable_map)?;
-                swap_values(right, variable_map)?;
+            Command::If(condition, commands) => {
+                swap_condition(condition, variable_map)?;
                 swap_commands(commands, variable_map)?;
             }
-            Command::IfElse(Condition{ operator, left, right }, commands1, commands2) => {
-                swap_values(left, variable_map)?;
-                swap_values(right, variable_map)?;
+            Command::IfElse(condition, commands1, commands2) => {
+                swap_condition(condition, variable_map)?;
                 swap_commands(commands1, variable_map)?;
                 swap_commands(commands2, variable_map)?;
             }
-            Command::While(Condition{ operator, left, right }, commands) => {
-                swap_values(left, variable_map)?;
-                swap_values(right, variable_map)?;
+            Command::Case(value, branches, otherwise) => {
+                swap_values(value, variable_map)?;
+                for branch in branches {
+                    swap_commands(&mut branch.commands, variable_map)?;
+                }
+                swap_commands(otherwise, variable_map)?;
+            }
+            Command::While(condition, commands) => {
+                swap_condition(condition, variable_map)?;
                 swap_commands(commands, variable_map)?;
             }
-            Command::Repeat(Condition{ operator, left, right }, commands) => {
-                swap_values(left, variable_map)?;
-                swap_values(right, variable_map)?;
+            Command::Repeat(condition, commands) => {
+                swap_condition(condition, variable_map)?;
                 swap_commands(commands, variable_map)?;
             }
-            Command::For(iter, first, second, commands) => {
+            Command::For(iter, first, second, step, commands) => {
                 *iter = variable_map.get(iter).unwrap().clone();
                 swap_values(first, variable_map)?;
                 swap_values(second, variable_map)?;
+                swap_values(step, variable_map)?;
                 swap_commands(commands, variable_map)?;
             }
-            Command::ForDown(iter, first, second, commands) => {
+            Command::ForDown(iter, first, second, step, commands) => {
                 *iter = variable_map.get(iter).unwrap().clone();
                 swap_values(first, variable_map)?;
                 swap_values(second, variable_map)?;
+                swap_values(step, variable_map)?;
                 swap_commands(commands, variable_map)?;
             }
             Command::FunctionCall(name, arguments) => {
@@ -54,17 +59,43 @@ pub fn swap_commands(commands: &mut Vec<Command>, variable_map: &HashMap<String,
             Command::Write(value) => {
                 swap_values(value, variable_map)?;
             }
+            Command::Asm(lines) => {
+                for line in lines {
+                    if let AsmLine::Instruction(_, AsmOperand::Identifier(identifier)) = line {
+                        swap_identifier(identifier, variable_map)?;
+                    }
+                }
+            }
+            Command::Break | Command::Continue | Command::Line(_) => {}
         }
     }
     Ok(())
 }
 
+fn swap_condition(condition: &mut Condition, variable_map: &HashMap<String, String>) -> Result<(), TranslationError> {
+    for comparison in condition.comparisons_mut() {
+        swap_commands(&mut comparison.before, variable_map)?;
+        swap_values(&mut comparison.left, variable_map)?;
+        swap_values(&mut comparison.right, variable_map)?;
+    }
+    Ok(())
+}
+
 fn swap_values(value: &mut Value, variable_map: &HashMap<String, String>) -> Result<(), TranslationError> {
     match value {
         Value::Literal(_) => {}
         Value::Identifier(identifier) => {
             swap_identifier(identifier, variable_map)?;
         }
+        Value::Call(_, arguments) => {
+            for argument in arguments {
+                *argument = variable_map.get(argument).unwrap().clone();
+            }
+        }
+        Value::Expression(operation) => {
+            swap_values(&mut operation.left, variable_map)?;
+            swap_values(&mut operation.right, variable_map)?;
+        }
     }
     Ok(())
 }
@@ -97,6 +128,16 @@ fn swap_identifier(identifier: &mut Identifier, variable_map: &HashMap<String, S
             };
             *variable = new_variable;
         }
+        Identifier::Element(name, indices) => {
+            let new_name = match variable_map.get(name) {
+                None => format!("@unid@{}", name),
+                Some(name) => name.clone()
+            };
+            *name = new_name;
+            for index in indices {
+                swap_values(index, variable_map)?;
+            }
+        }
     }
     Ok(())
 }
\ No newline at end of file
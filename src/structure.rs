@@ -1,51 +1,318 @@
 use std::fmt::{Debug, Display, Formatter};
 
 pub struct Program {
+    /// Names and values given in `CONST`, replaced by the values before translation.
+    pub constants: Vec<(String, i64)>,
+    /// Variables and arrays of `GLOBAL`, visible in the main program and in procedures.
+    pub globals: Vec<Declaration>,
     pub procedures: Vec<Procedure>,
     pub declarations: Vec<Declaration>,
     pub commands: Vec<Command>,
+    /// Line of every command, commands of procedures first, in the order they start in the source.
+    pub lines: Vec<usize>,
+}
+
+impl Program {
+    /// Takes markers left by the parser out of commands into `lines`, numbering lines of the source.
+    pub fn new(
+        source: &str,
+        constants: Vec<(String, i64)>,
+        globals: Vec<Declaration>,
+        mut procedures: Vec<Procedure>,
+        declarations: Vec<Declaration>,
+        mut commands: Vec<Command>,
+    ) -> Program {
+        let breaks: Vec<usize> = source.match_indices('\n').map(|(offset, _)| offset).collect();
+        let mut lines = vec![];
+        for procedure in procedures.iter_mut() {
+            take_lines(&mut procedure.commands, &breaks, &mut lines);
+        }
+        take_lines(&mut commands, &breaks, &mut lines);
+        Program {
+            constants,
+            globals,
+            procedures,
+            declarations,
+            commands,
+            lines,
+        }
+    }
+}
+
+/// Moves markers out of commands, `breaks` are offsets of line breaks in the source.
+fn take_lines(commands: &mut Vec<Command>, breaks: &[usize], lines: &mut Vec<usize>) {
+    let mut line = 0;
+    commands.retain_mut(|command| {
+        if let Command::Line(offset) = command {
+            line = breaks.partition_point(|line_break| line_break < offset) + 1;
+            return false;
+        }
+        lines.push(line);
+        match command {
+            Command::If(_, body)
+            | Command::While(_, body)
+            | Command::Repeat(_, body)
+            | Command::For(_, _, _, _, body)
+            | Command::ForDown(_, _, _, _, body) => take_lines(body, breaks, lines),
+            Command::IfElse(_, body, otherwise) => {
+                take_lines(body, breaks, lines);
+                take_lines(otherwise, breaks, lines);
+            }
+            Command::Case(_, branches, otherwise) => {
+                for branch in branches.iter_mut() {
+                    take_lines(&mut branch.commands, breaks, lines);
+                }
+                take_lines(otherwise, breaks, lines);
+            }
+            _ => {}
+        }
+        true
+    });
 }
 pub struct Procedure {
     pub(crate) name: String,
     pub(crate) arguments: Vec<ArgumentDecl>,
     pub(crate) declarations: Vec<Declaration>,
     pub(crate) commands: Vec<Command>,
+    /// Inlining forced (`INLINE`) or forbidden (`NOINLINE`) by the programmer.
+    pub(crate) inline: Option<bool>,
+    /// Marked `RECURSIVE`, the procedure can call itself and keeps its variables on a stack.
+    pub(crate) recursive: bool,
+    /// Value given by `RETURN` of a `FUNCTION`, procedures have none.
+    pub(crate) returns: Option<Value>,
 }
 
 #[derive(Debug, Clone)]
 pub enum ArgumentDecl {
     VariableArg(String),
     ArrayArg(String),
+    /// Array with the given number of dimensions, its row lengths are passed along with it.
+    MatrixArg(String, usize),
 }
 
 #[derive(Debug, Clone)]
 pub enum Declaration {
     VariableDecl(String),
     ArrayDecl(String, i64, i64),
+    /// Array with bounds of every dimension, flattened row by row before translation.
+    MatrixDecl(String, Vec<(i64, i64)>),
+    /// Array with bounds given by names of constants, replaced by `ArrayDecl` or `MatrixDecl` when they are folded.
+    NamedBoundsDecl(String, Vec<(Value, Value)>),
     ConstantDecl(String),
 }
 
+impl Declaration {
+    /// Array declared with the bounds, with more than one dimension it is a matrix.
+    pub fn array(name: String, mut bounds: Vec<(i64, i64)>) -> Declaration {
+        if bounds.len() == 1 {
+            let (first, last) = bounds.remove(0);
+            Declaration::ArrayDecl(name, first, last)
+        } else {
+            Declaration::MatrixDecl(name, bounds)
+        }
+    }
+
+    /// Array declared with bounds given by numbers or names of constants.
+    pub fn with_bounds(name: String, bounds: Vec<(Value, Value)>) -> Declaration {
+        let literals: Option<Vec<(i64, i64)>> = bounds
+            .iter()
+            .map(|bound| match bound {
+                (Value::Literal(first), Value::Literal(last)) => Some((*first, *last)),
+                _ => None,
+            })
+            .collect();
+        match literals {
+            Some(literals) => Declaration::array(name, literals),
+            None => Declaration::NamedBoundsDecl(name, bounds),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub enum Command {
     Assign(Identifier, Operation),
     If(Condition, Vec<Command>),
     IfElse(Condition, Vec<Command>, Vec<Command>),
     While(Condition, Vec<Command>),
     Repeat(Condition, Vec<Command>),
-    For(String, Value, Value, Vec<Command>),
-    ForDown(String, Value, Value, Vec<Command>),
+    /// Iterator, first value, last value and positive step, fixed when the loop starts.
+    For(String, Value, Value, Value, Vec<Command>),
+    ForDown(String, Value, Value, Value, Vec<Command>),
     FunctionCall(String, Vec<String>),
     Read(Identifier),
     Write(Value),
+    Asm(Vec<AsmLine>),
+    /// Leaves the innermost loop.
+    Break,
+    /// Goes to the next iteration of the innermost loop.
+    Continue,
+    /// Value, branches checked in order and commands of `ELSE`.
+    Case(Value, Vec<CaseBranch>, Vec<Command>),
+    /// Offset in the source where the next command starts, only until the parser builds the program.
+    Line(usize),
+}
+
+/// Branch of `CASE` taken when the value is in one of the ranges.
+#[derive(Debug, Clone)]
+pub struct CaseBranch {
+    /// Inclusive ranges, a single value is a range of length one.
+    pub(crate) ranges: Vec<(i64, i64)>,
+    pub(crate) commands: Vec<Command>,
+}
+
+/// Line of an `ASM` block, a label or an instruction of the virtual machine.
+#[derive(Debug, Clone)]
+pub enum AsmLine {
+    Label(String),
+    Instruction(String, AsmOperand),
+}
+
+#[derive(Debug, Clone)]
+pub enum AsmOperand {
+    None,
+    /// Cell number or offset of a jump.
+    Number(i64),
+    /// Literal written as `=value`.
+    Literal(i64),
+    Identifier(Identifier),
+    Label(String),
+}
+
+impl AsmLine {
+    /// Instruction with the operand of a jump treated as a label.
+    pub fn instruction(mnemonic: &str, operand: AsmOperand) -> AsmLine {
+        let operand = match operand {
+            AsmOperand::Identifier(Identifier::Variable(name)) if is_jump(mnemonic) => {
+                AsmOperand::Label(name)
+            }
+            operand => operand,
+        };
+        AsmLine::Instruction(mnemonic.to_string(), operand)
+    }
+
+    /// Variable or array element used by the instruction.
+    pub fn identifier(&self) -> Option<&Identifier> {
+        match self {
+            AsmLine::Instruction(_, AsmOperand::Identifier(identifier)) => Some(identifier),
+            _ => None,
+        }
+    }
+
+    /// Whether the instruction overwrites its operand.
+    pub fn writes(&self) -> bool {
+        matches!(self, AsmLine::Instruction(mnemonic, _) if mnemonic == "STORE" || mnemonic == "GET")
+    }
+
+    /// Whether the instruction can write to any cell.
+    pub fn writes_indirectly(&self) -> bool {
+        matches!(self, AsmLine::Instruction(mnemonic, _) if mnemonic == "STOREI")
+    }
+}
+
+/// Whether the loop body contains `BREAK` of this loop, not of a nested one.
+pub fn has_break(commands: &[Command]) -> bool {
+    controls_loop_with(commands, &|command| matches!(command, Command::Break))
 }
 
-#[derive(Debug)]
-pub struct Condition {
+/// Whether the loop body contains `CONTINUE` of this loop, not of a nested one.
+pub fn has_continue(commands: &[Command]) -> bool {
+    controls_loop_with(commands, &|command| matches!(command, Command::Continue))
+}
+
+/// Whether the loop body can leave the iteration early, so it can not be copied or unwrapped.
+pub fn controls_loop(commands: &[Command]) -> bool {
+    has_break(commands) || has_continue(commands)
+}
+
+fn controls_loop_with(commands: &[Command], control: &dyn Fn(&Command) -> bool) -> bool {
+    commands.iter().any(|command| match command {
+        Command::If(_, commands) => controls_loop_with(commands, control),
+        Command::IfElse(_, commands1, commands2) => {
+            controls_loop_with(commands1, control) || controls_loop_with(commands2, control)
+        }
+        Command::Case(_, branches, otherwise) => {
+            branches
+                .iter()
+                .any(|branch| controls_loop_with(&branch.commands, control))
+                || controls_loop_with(otherwise, control)
+        }
+        // Nested loops handle their own
+        Command::While(_, _)
+        | Command::Repeat(_, _)
+        | Command::For(_, _, _, _, _)
+        | Command::ForDown(_, _, _, _, _) => false,
+        Command::Assign(_, _)
+        | Command::FunctionCall(_, _)
+        | Command::Read(_)
+        | Command::Write(_)
+        | Command::Asm(_)
+        | Command::Break
+        | Command::Continue
+        | Command::Line(_) => control(command),
+    })
+}
+
+pub fn is_jump(mnemonic: &str) -> bool {
+    matches!(mnemonic, "JUMP" | "JPOS" | "JZERO" | "JNEG")
+}
+
+#[derive(Debug, Clone)]
+pub struct Comparison {
     pub(crate) operator: ConditionOperator,
     pub(crate) left: Value,
     pub(crate) right: Value,
+    /// Commands computing operands and checking them, run only when the comparison is checked.
+    pub(crate) before: Vec<Command>,
+}
+
+impl Comparison {
+    pub fn new(operator: ConditionOperator, left: Value, right: Value) -> Comparison {
+        Comparison {
+            operator,
+            left,
+            right,
+            before: vec![],
+        }
+    }
 }
 
-#[derive(Debug)]
+/// Comparisons joined with `AND`, `OR` and `NOT`, checked with short-circuit jumps.
+#[derive(Debug, Clone)]
+pub enum Condition {
+    Compare(Comparison),
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+impl Condition {
+    /// Comparisons in the order they are checked.
+    pub fn comparisons(&self) -> Vec<&Comparison> {
+        match self {
+            Condition::Compare(comparison) => vec![comparison],
+            Condition::Not(condition) => condition.comparisons(),
+            Condition::And(first, second) | Condition::Or(first, second) => {
+                let mut comparisons = first.comparisons();
+                comparisons.extend(second.comparisons());
+                comparisons
+            }
+        }
+    }
+
+    pub fn comparisons_mut(&mut self) -> Vec<&mut Comparison> {
+        match self {
+            Condition::Compare(comparison) => vec![comparison],
+            Condition::Not(condition) => condition.comparisons_mut(),
+            Condition::And(first, second) | Condition::Or(first, second) => {
+                let mut comparisons = first.comparisons_mut();
+                comparisons.extend(second.comparisons_mut());
+                comparisons
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ConditionOperator {
     Equal,
     NotEqual,
@@ -55,10 +322,34 @@ pub enum ConditionOperator {
     GreaterEqual,
 }
 
-#[derive(Debug)]
+impl ConditionOperator {
+    /// Operator of the opposite comparison.
+    pub fn negated(self) -> ConditionOperator {
+        match self {
+            ConditionOperator::Equal => ConditionOperator::NotEqual,
+            ConditionOperator::NotEqual => ConditionOperator::Equal,
+            ConditionOperator::Lesser => ConditionOperator::GreaterEqual,
+            ConditionOperator::Greater => ConditionOperator::LesserEqual,
+            ConditionOperator::LesserEqual => ConditionOperator::Greater,
+            ConditionOperator::GreaterEqual => ConditionOperator::Lesser,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Value {
     Literal(i64),
     Identifier(Identifier),
+    /// Call of a function, replaced by a variable holding its result before translation.
+    Call(String, Vec<String>),
+    /// Operation nested in another, computed into a scratch cell.
+    Expression(Box<Operation>),
+}
+
+impl Value {
+    pub fn operation(operator: Operator, left: Value, right: Value) -> Value {
+        Value::Expression(Box::new(Operation { operator, left, right }))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -66,6 +357,23 @@ pub enum Identifier {
     Variable(String),
     ArrayLit(String, i64),
     ArrayVar(String, String),
+    /// Element with indices computed from any values, one for every dimension of the array,
+    /// replaced by `ArrayVar` or `ArrayLit` before translation.
+    Element(String, Vec<Value>),
+}
+
+impl Identifier {
+    /// Element of the array, a single literal or variable index is used directly.
+    pub fn element(name: String, mut indices: Vec<Value>) -> Identifier {
+        if indices.len() == 1 {
+            match indices.pop().unwrap() {
+                Value::Literal(index) => return Identifier::ArrayLit(name, index),
+                Value::Identifier(Identifier::Variable(index)) => return Identifier::ArrayVar(name, index),
+                index => indices.push(index),
+            }
+        }
+        Identifier::Element(name, indices)
+    }
 }
 
 // #[derive(Debug)]
@@ -78,14 +386,14 @@ pub enum Identifier {
 //     Value(Value),
 // }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Operation {
     pub(crate) operator: Operator,
     pub(crate) left: Value,
     pub(crate) right: Value,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Operator {
     Add,
     Subtract,
@@ -157,16 +465,16 @@ impl Debug for Command {
                 }
                 write!(f, "Until condition {:?} \n", cond)
             }
-            Command::For(var, from, to, commands) => {
-                write!(f, "For {} from {:?} to {:?} do commands: \n", var, from, to)?;
+            Command::For(var, from, to, step, commands) => {
+                write!(f, "For {} from {:?} to {:?} step {:?} do commands: \n", var, from, to, step)?;
                 for command in commands {
                     write!(f, "{:?}\n", command)?;
                 }
                 write!(f, "End for")?;
                 Ok(())
             }
-            Command::ForDown(var, from, to, commands) => {
-                write!(f, "For {} from {:?} downto {:?} do commands: \n", var, from, to)?;
+            Command::ForDown(var, from, to, step, commands) => {
+                write!(f, "For {} from {:?} downto {:?} step {:?} do commands: \n", var, from, to, step)?;
                 for command in commands {
                     write!(f, "{:?}\n", command)?;
                 }
@@ -176,6 +484,30 @@ impl Debug for Command {
             Command::FunctionCall(name, args) => write!(f, "Call function \"{}\" with args {:?}", name, args),
             Command::Read(var) => write!(f, "Read value to {:?}", var),
             Command::Write(val) => write!(f, "Write value {:?}", val),
+            Command::Asm(lines) => {
+                writeln!(f, "Asm: ")?;
+                for line in lines {
+                    writeln!(f, "{:?}", line)?;
+                }
+                write!(f, "End asm")
+            }
+            Command::Break => write!(f, "Break"),
+            Command::Line(line) => write!(f, "Line {}", line),
+            Command::Continue => write!(f, "Continue"),
+            Command::Case(value, branches, otherwise) => {
+                writeln!(f, "Case {:?} of: ", value)?;
+                for branch in branches {
+                    writeln!(f, "Ranges {:?} commands: ", branch.ranges)?;
+                    for command in &branch.commands {
+                        writeln!(f, "{:?}", command)?;
+                    }
+                }
+                writeln!(f, "Else commands: ")?;
+                for command in otherwise {
+                    writeln!(f, "{:?}", command)?;
+                }
+                write!(f, "End case")
+            }
         }
     }
 }
@@ -204,6 +536,20 @@ impl Debug for Procedure {
     }
 }
 
+impl Operation {
+    /// Operation giving the value, nested operation is taken out.
+    pub fn of_value(value: Value) -> Operation {
+        match value {
+            Value::Expression(operation) => *operation,
+            value => Operation {
+                operator: Operator::Value,
+                left: value,
+                right: Value::Literal(0),
+            },
+        }
+    }
+}
+
 impl Display for Operation {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self.operator {
@@ -232,12 +578,44 @@ impl Display for Operator {
     }
 }
 
-impl Display for Condition {
+impl Display for AsmLine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AsmLine::Label(label) => write!(f, "{}:", label),
+            AsmLine::Instruction(mnemonic, AsmOperand::None) => write!(f, "{}", mnemonic),
+            AsmLine::Instruction(mnemonic, AsmOperand::Number(number)) => {
+                write!(f, "{} {}", mnemonic, number)
+            }
+            AsmLine::Instruction(mnemonic, AsmOperand::Literal(literal)) => {
+                write!(f, "{} ={}", mnemonic, literal)
+            }
+            AsmLine::Instruction(mnemonic, AsmOperand::Identifier(identifier)) => {
+                write!(f, "{} {}", mnemonic, Value::Identifier(identifier.clone()))
+            }
+            AsmLine::Instruction(mnemonic, AsmOperand::Label(label)) => {
+                write!(f, "{} {}", mnemonic, label)
+            }
+        }
+    }
+}
+
+impl Display for Comparison {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "{} {} {}", self.left, self.operator, self.right)
     }
 }
 
+impl Display for Condition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Condition::Compare(comparison) => write!(f, "{}", comparison),
+            Condition::Not(condition) => write!(f, "NOT ({})", condition),
+            Condition::And(first, second) => write!(f, "({}) AND ({})", first, second),
+            Condition::Or(first, second) => write!(f, "({}) OR ({})", first, second),
+        }
+    }
+}
+
 impl Display for ConditionOperator {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -266,6 +644,16 @@ impl Display for Value {
             Value::Identifier(Identifier::ArrayVar(name, var)) => {
                 write!(f, "arr_{}[var {}]", remove_program_things(name), remove_program_things(var))
             }
+            Value::Identifier(Identifier::Element(name, indices)) => {
+                let indices: Vec<String> = indices.iter().map(|index| index.to_string()).collect();
+                write!(f, "arr_{}[{}]", remove_program_things(name), indices.join(", "))
+            }
+            Value::Call(name, arguments) => {
+                write!(f, "call_{}({})", name, arguments.join(", "))
+            }
+            Value::Expression(operation) => {
+                write!(f, "({})", operation)
+            }
         }
     }
 }
@@ -7,7 +7,27 @@ pub struct VariableDictionary {
     variables: HashMap<String, Variable>,
     arrays: HashMap<String, Array>,
     cell_counter: usize,
+    frame: Option<Frame>,
 }
+
+/// Stack frame of a recursive procedure, holding its arguments and local variables.
+///
+/// Every argument, variable and array gets a static register cell,
+/// which has to be set for the current frame as given by `FrameSlot`.
+#[derive(Debug, Default)]
+pub struct Frame {
+    pub size: usize,
+    pub registers: Vec<(usize, FrameSlot)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum FrameSlot {
+    /// Register is set to the value kept in the slot, address of the argument or its array offset.
+    Argument(usize),
+    /// Register is set to address of the frame increased by the offset.
+    Local(i64),
+}
+
 #[derive(Debug)]
 struct Variable {
     cell: Pointer,
@@ -27,7 +47,7 @@ pub enum Type {
     Array(Pointer, Pointer),
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Pointer {
     Cell(usize),
     IndirectCell(usize),
@@ -95,12 +115,56 @@ impl Debug for VariableError {
         }
     }
 }
+/// Distance between elements with consecutive indices in every dimension, rows are stored one after another.
+pub fn strides(bounds: &[(i64, i64)]) -> Vec<i64> {
+    let mut strides = vec![1; bounds.len()];
+    for dimension in (1..bounds.len()).rev() {
+        let (first, last) = bounds[dimension];
+        strides[dimension - 1] = strides[dimension] * (last - first + 1);
+    }
+    strides
+}
+
 impl VariableDictionary {
     pub fn new(start: usize) -> VariableDictionary {
         VariableDictionary {
             variables: HashMap::new(),
             arrays: HashMap::new(),
             cell_counter: start,
+            frame: None,
+        }
+    }
+
+    /// Places arguments and variables declared later in a stack frame, after `reserved` slots.
+    pub fn use_frame(&mut self, reserved: usize) {
+        self.frame = Some(Frame {
+            size: reserved,
+            registers: vec![],
+        });
+    }
+
+    pub fn frame(&self) -> Option<&Frame> {
+        self.frame.as_ref()
+    }
+
+    /// Takes slots in the frame and a register cell for them.
+    fn frame_register(&mut self, len: usize, slot: impl Fn(usize) -> FrameSlot) -> Option<usize> {
+        let frame = self.frame.as_mut()?;
+        let register = self.cell_counter;
+        frame.registers.push((register, slot(frame.size)));
+        frame.size += len;
+        self.cell_counter += 1;
+        Some(register)
+    }
+
+    /// Cell of a new variable, in frame the variable is reached through a register.
+    fn variable_cell(&mut self) -> Pointer {
+        match self.frame_register(1, |slot| FrameSlot::Local(slot as i64)) {
+            Some(register) => Pointer::IndirectCell(register),
+            None => {
+                self.cell_counter += 1;
+                Pointer::Cell(self.cell_counter - 1)
+            }
         }
     }
 
@@ -118,41 +182,56 @@ impl VariableDictionary {
         match var {
             Declaration::VariableDecl(name) => {
                 self.check_name(&name)?;
+                let cell = self.variable_cell();
                 self.variables.insert(
                     name,
                     Variable {
-                        cell: Pointer::Cell(self.cell_counter),
+                        cell,
                         init: false,
                         constant: false,
                     },
                 );
-                self.cell_counter += 1;
             }
             Declaration::ArrayDecl(name, from, to) => {
                 self.check_name(&name)?;
                 let len = (to - from + 1) as usize;
-                let offset = self.cell_counter as i64 - from;
+                let offset = match self.frame_register(len, |slot| FrameSlot::Local(slot as i64 - from)) {
+                    Some(register) => Pointer::Cell(register),
+                    None => {
+                        self.cell_counter += len;
+                        Pointer::Literal(self.cell_counter as i64 - len as i64 - from)
+                    }
+                };
                 self.arrays.insert(
                     name,
                     Array {
-                        offset: Pointer::Literal(offset),
+                        offset,
                         start: from,
                         length: len,
                     },
                 );
-                self.cell_counter += len;
+            }
+            Declaration::MatrixDecl(name, bounds) => {
+                // Rows are placed one after another, element at indices is at their sum weighted by strides
+                let strides = strides(&bounds);
+                let from = bounds.iter().zip(&strides).map(|((first, _), stride)| first * stride).sum();
+                let to = bounds.iter().zip(&strides).map(|((_, last), stride)| last * stride).sum();
+                self.add(Declaration::ArrayDecl(name, from, to))?;
+            }
+            Declaration::NamedBoundsDecl(name, _) => {
+                panic!("Bounds of {} not folded", name);
             }
             Declaration::ConstantDecl(name) => {
                 self.check_name(&name)?;
+                let cell = self.variable_cell();
                 self.variables.insert(
                     name,
                     Variable {
-                        cell: Pointer::Cell(self.cell_counter),
+                        cell,
                         init: true,
                         constant: true,
                     },
                 );
-                self.cell_counter += 1;
             }
         }
         Ok(())
@@ -162,32 +241,40 @@ impl VariableDictionary {
         match var {
             ArgumentDecl::VariableArg(name) => {
                 self.check_name(&name)?;
+                let cell = self.argument_cell();
                 self.variables.insert(
                     name,
                     Variable {
-                        cell: Pointer::IndirectCell(self.cell_counter),
+                        cell: Pointer::IndirectCell(cell),
                         init: true,
                         constant: false,
                     },
                 );
-                self.cell_counter += 1;
             }
-            ArgumentDecl::ArrayArg(name) => {
+            ArgumentDecl::ArrayArg(name) | ArgumentDecl::MatrixArg(name, _) => {
                 self.check_name(&name)?;
+                let cell = self.argument_cell();
                 self.arrays.insert(
                     name,
                     Array {
-                        offset: Pointer::Cell(self.cell_counter),
+                        offset: Pointer::Cell(cell),
                         start: 0,
                         length: 0,
                     },
                 );
-                self.cell_counter += 1;
             }
         }
         Ok(())
     }
 
+    /// Cell holding address of an argument, in frame it is a register set from the argument slot.
+    fn argument_cell(&mut self) -> usize {
+        self.frame_register(1, FrameSlot::Argument).unwrap_or_else(|| {
+            self.cell_counter += 1;
+            self.cell_counter - 1
+        })
+    }
+
     fn get_variable(&self, name: &str) -> Result<&Variable, VariableError> {
         match self.variables.get(name) {
             None => {
@@ -234,6 +321,11 @@ impl VariableDictionary {
                         }
                     }
                     Pointer::Cell(_) => {
+                        // Length of arrays passed as arguments is not known
+                        let translated_index = index - array.start;
+                        if array.length > 0 && (translated_index < 0 || translated_index >= array.length as i64) {
+                            return Err(VariableError::InvalidIndex(name, translated_index));
+                        }
                         Ok(Type::Array(array.offset, Pointer::Literal(index)))
                     }
                     _ => {
@@ -247,6 +339,9 @@ impl VariableDictionary {
                 let array = self.get_array(&name)?;
                 Ok(Type::Array(array.offset, variable.cell))
             }
+            Identifier::Element(name, _) => {
+                panic!("Element of {} not lowered", name);
+            }
         }
     }
 
@@ -286,6 +381,8 @@ impl VariableDictionary {
             Value::Identifier(identifier) => {
                 self.read_identifier(identifier)
             }
+            Value::Call(name, _) => panic!("Call of {} was not lowered", name),
+            Value::Expression(operation) => panic!("Operation {} was not computed", operation),
         }
     }
 
@@ -297,6 +394,8 @@ impl VariableDictionary {
             Value::Identifier(identifier) => {
                 self.write_identifier(identifier)
             }
+            Value::Call(name, _) => panic!("Call of {} was not lowered", name),
+            Value::Expression(operation) => panic!("Operation {} was not computed", operation),
         }
     }
 
@@ -312,6 +411,12 @@ impl VariableDictionary {
         }
     }
 
+    /// Whether the name refers to a procedure argument passed by reference.
+    pub fn is_reference(&self, name: &str) -> bool {
+        matches!(self.variables.get(name), Some(Variable { cell: Pointer::IndirectCell(_), .. }))
+            || matches!(self.arrays.get(name), Some(Array { offset: Pointer::Cell(_), .. }))
+    }
+
     pub fn get_array_offset(&self, name: &str) -> Result<Pointer, VariableError> {
         let array = self.get_array(name)?;
         Ok(array.offset)
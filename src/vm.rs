@@ -0,0 +1,205 @@
+#[cfg(test)]
+use crate::intermediate::program_translator::Translator;
+#[cfg(test)]
+use crate::optimizer::pipeline::Options;
+use std::collections::HashMap;
+
+/// Output and cost of a finished program.
+#[derive(Debug, PartialEq)]
+pub struct Execution {
+    pub output: Vec<i64>,
+    pub cost: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Code {
+    Get,
+    Put,
+    Load,
+    Store,
+    Loadi,
+    Storei,
+    Add,
+    Sub,
+    Addi,
+    Subi,
+    Set,
+    Half,
+    Jump,
+    Jpos,
+    Jzero,
+    Jneg,
+    Rtrn,
+    Halt,
+}
+
+fn parse(code: &str) -> Result<Vec<(Code, i64)>, String> {
+    code.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            // Comments are placed after '#'
+            let line = line.split('#').next().unwrap_or_default();
+            let mut parts = line.split_whitespace();
+            let name = parts.next().unwrap_or_default();
+            let argument = match parts.next() {
+                Some(argument) => argument
+                    .parse()
+                    .map_err(|_| format!("Invalid argument in {}", line))?,
+                None => 0,
+            };
+            let code = match name {
+                "GET" => Code::Get,
+                "PUT" => Code::Put,
+                "LOAD" => Code::Load,
+                "STORE" => Code::Store,
+                "LOADI" => Code::Loadi,
+                "STOREI" => Code::Storei,
+                "ADD" => Code::Add,
+                "SUB" => Code::Sub,
+                "ADDI" => Code::Addi,
+                "SUBI" => Code::Subi,
+                "SET" => Code::Set,
+                "HALF" => Code::Half,
+                "JUMP" => Code::Jump,
+                "JPOS" => Code::Jpos,
+                "JZERO" => Code::Jzero,
+                "JNEG" => Code::Jneg,
+                "RTRN" => Code::Rtrn,
+                "HALT" => Code::Halt,
+                _ => return Err(format!("Unknown instruction {}", line)),
+            };
+            Ok((code, argument))
+        })
+        .collect()
+}
+
+/// Runs the code the same way as the virtual machine does, with the same costs.
+/// Fails on invalid addresses, missing input or after `max_steps` instructions.
+pub fn run(code: &str, input: &[i64], max_steps: usize) -> Result<Execution, String> {
+    let program = parse(code)?;
+    let mut memory: HashMap<i64, i64> = HashMap::new();
+    let mut input = input.iter();
+    let mut output = vec![];
+    let mut cost = 0;
+    let mut lr: i64 = 0;
+
+    for _ in 0..max_steps {
+        let (code, argument) = *program
+            .get(lr as usize)
+            .filter(|_| lr >= 0)
+            .ok_or_else(|| format!("Jump to nonexistent instruction {}", lr))?;
+        let is_jump = matches!(
+            code,
+            Code::Set | Code::Jump | Code::Jpos | Code::Jzero | Code::Jneg
+        );
+        if !is_jump && argument < 0 {
+            return Err(format!("Negative address in instruction {}", lr));
+        }
+        let value = |cell: i64| memory.get(&cell).copied().unwrap_or(0);
+        let accumulator = value(0);
+        let indirect = value(value(argument));
+        let mut next = lr + 1;
+        match code {
+            Code::Get => {
+                let read = *input.next().ok_or("Not enough input")?;
+                memory.insert(argument, read);
+                cost += 100;
+            }
+            Code::Put => {
+                output.push(value(argument));
+                cost += 100;
+            }
+            Code::Load => {
+                memory.insert(0, value(argument));
+                cost += 10;
+            }
+            Code::Store => {
+                memory.insert(argument, accumulator);
+                cost += 10;
+            }
+            Code::Loadi => {
+                memory.insert(0, indirect);
+                cost += 20;
+            }
+            Code::Storei => {
+                memory.insert(value(argument), accumulator);
+                cost += 20;
+            }
+            Code::Add => {
+                memory.insert(0, accumulator.wrapping_add(value(argument)));
+                cost += 10;
+            }
+            Code::Sub => {
+                memory.insert(0, accumulator.wrapping_sub(value(argument)));
+                cost += 10;
+            }
+            Code::Addi => {
+                memory.insert(0, accumulator.wrapping_add(indirect));
+                cost += 20;
+            }
+            Code::Subi => {
+                memory.insert(0, accumulator.wrapping_sub(indirect));
+                cost += 12;
+            }
+            Code::Set => {
+                memory.insert(0, argument);
+                cost += 50;
+            }
+            Code::Half => {
+                memory.insert(0, accumulator >> 1);
+                cost += 5;
+            }
+            Code::Jump => {
+                next = lr + argument;
+                cost += 1;
+            }
+            Code::Jpos | Code::Jzero | Code::Jneg => {
+                let jump = match code {
+                    Code::Jpos => accumulator > 0,
+                    Code::Jzero => accumulator == 0,
+                    _ => accumulator < 0,
+                };
+                if jump {
+                    next = lr + argument;
+                }
+                cost += 1;
+            }
+            Code::Rtrn => {
+                next = value(argument);
+                cost += 10;
+            }
+            Code::Halt => return Ok(Execution { output, cost }),
+        }
+        lr = next;
+    }
+    Err(format!("Program did not stop after {} instructions", max_steps))
+}
+
+/// Parses and translates the source with given options, for tests.
+#[cfg(test)]
+pub fn compile(source: &str, options: Options) -> Translator {
+    let mut translator = Translator::new();
+    translator.options = options;
+    translator
+        .translate(crate::grammar::program_allParser::new().parse(source).unwrap())
+        .unwrap();
+    translator
+}
+
+/// Compiles the source at optimization level given after `-O` and returns values it writes for the input,
+/// for tests.
+#[cfg(test)]
+pub fn compile_and_run(source: &str, level: &str, input: &[i64]) -> Vec<i64> {
+    let code = compile(source, Options::level(level).unwrap()).to_code(false);
+    run(&code, input, 1_000_000).unwrap().output
+}
+
+#[test]
+fn runs_program_with_costs() {
+    let code = "GET 1\nLOAD 1\nJZERO 4\nSUB 2\nSTORE 1\nHALF\nPUT 1\nHALT";
+    let execution = run(code, &[5], 100).unwrap();
+    assert_eq!(execution.output, vec![5]);
+    assert_eq!(execution.cost, 100 + 10 + 1 + 10 + 10 + 5 + 100);
+    assert!(run(code, &[], 100).is_err());
+    assert!(run("JUMP 0", &[], 100).is_err());
+}